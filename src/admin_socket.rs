@@ -0,0 +1,72 @@
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+// Ceph daemons can stall for a while but shouldn't hold up a collector
+// indefinitely
+const TIMEOUT_SECS: u64 = 10;
+// Far more than any perf dump, but a corrupt length shouldn't be able to
+// allocate gigabytes
+const MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn test_response_too_long() {
+        let path = env::temp_dir().join("admin_socket_test.asok");
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0u8; 64];
+            let _ = stream.read(&mut command);
+            let _ = stream.write_all(&[0xff, 0xff, 0xff, 0xff]);
+        });
+        let err = super::perf_dump(&path).unwrap_err();
+        assert!(err.contains("too long"), err);
+        let _ = fs::remove_file(&path);
+    }
+}
+
+/// Run `perf dump` against the admin socket of a running Ceph daemon and
+/// return the raw JSON it answers with
+pub fn perf_dump(socket: &Path) -> Result<String, String> {
+    admin_socket_command(socket, "perf dump")
+}
+
+/// Run `perf schema`, which describes the type of every counter in the
+/// `perf dump`
+pub fn perf_schema(socket: &Path) -> Result<String, String> {
+    admin_socket_command(socket, "perf schema")
+}
+
+// The admin socket protocol: send a NUL terminated JSON command, then read
+// a 4 byte big endian length followed by that many bytes of response
+fn admin_socket_command(socket: &Path, prefix: &str) -> Result<String, String> {
+    let mut stream = try!(UnixStream::connect(socket).map_err(|e| format!("{}: {}", socket.display(), e)));
+    let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+    try!(stream.set_read_timeout(timeout).map_err(|e| e.to_string()));
+    try!(stream.set_write_timeout(timeout).map_err(|e| e.to_string()));
+
+    let command = format!("{{\"prefix\": \"{}\"}}\0", prefix);
+    try!(stream.write_all(command.as_bytes()).map_err(|e| e.to_string()));
+
+    let mut len_buf = [0u8; 4];
+    try!(stream.read_exact(&mut len_buf).map_err(|e| e.to_string()));
+    let len = ((len_buf[0] as usize) << 24) | ((len_buf[1] as usize) << 16) |
+              ((len_buf[2] as usize) << 8) | (len_buf[3] as usize);
+
+    if len > MAX_RESPONSE_BYTES {
+        return Err(format!("{}: response of {} bytes is too long", socket.display(), len));
+    }
+    let mut response = vec![0u8; len];
+    try!(stream.read_exact(&mut response).map_err(|e| e.to_string()));
+    String::from_utf8(response).map_err(|e| e.to_string())
+}
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use config::SharedArgs;
+use metric::Metric;
+
+// Agents send every few seconds, one that's gone quiet for this long has
+// gone away without closing its connection
+const IDLE_SECS: u64 = 300;
+
+// Far more than any daemon's full perf dump, an agent sending a longer line
+// without a newline is dropped rather than buffered
+const MAX_LINE_BYTES: u64 = 1024 * 1024;
+
+// What an agent sends first when the aggregator has a token
+const AUTH_PREFIX: &'static str = "AUTH ";
+
+// The fields of `mon_daemon` that every monitor of a cluster reports alike:
+// the curated ones, and a full perf dump's `cluster` section
+const CLUSTER_FIELDS: &'static [&'static str] = &["used", "avail", "total", "osds", "osds_up", "osds_in", "osd_epoch",
+                                                  "pgs", "pgs_active_clean", "ppgs_active", "pgs_peering", "objects",
+                                                  "objects_degraded", "objects_unfound", "monitors", "monitors_quorum"];
+const CLUSTER_PREFIX: &'static str = "cluster_";
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    use log::LogLevel;
+
+    use config::{self, SharedArgs};
+    use metric::{Metric, Value};
+
+    // An agent's connection to `receive`, and what it passes on
+    fn connect(yaml: &str) -> (TcpStream, Receiver<Metric>) {
+        let shared = SharedArgs::new(config::parse(yaml, "test.yaml", &[], LogLevel::Info).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let agent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || super::receive(stream, shared, tx, Arc::new(Mutex::new(super::Dedupe::new()))));
+        (agent, rx)
+    }
+
+    fn line() -> String {
+        format!("{}\n", Metric::new("osd_daemon").to_forward_json())
+    }
+
+    // Whether the aggregator hung up, reading until it does
+    fn dropped(agent: &mut TcpStream) -> bool {
+        let mut buf = [0; 16];
+        agent.read(&mut buf).unwrap_or(0) == 0
+    }
+
+    #[test]
+    fn test_receive() {
+        let (mut agent, rx) = connect("mode: aggregator\noutputs: [stdout]");
+        agent.write_all(line().as_bytes()).unwrap();
+        assert_eq!(rx.recv().unwrap().name, "osd_daemon");
+
+        // A line that never ends
+        agent.write_all(&vec![b'x'; super::MAX_LINE_BYTES as usize]).unwrap();
+        assert!(dropped(&mut agent));
+    }
+
+    #[test]
+    fn test_receive_token() {
+        let yaml = "mode: aggregator\noutputs: [stdout]\naggregator: {token: s3cret}";
+        let (mut agent, rx) = connect(yaml);
+        agent.write_all(format!("AUTH s3cret\n{}", line()).as_bytes()).unwrap();
+        assert_eq!(rx.recv().unwrap().name, "osd_daemon");
+
+        let (mut agent, rx) = connect(yaml);
+        agent.write_all(format!("AUTH guess\n{}", line()).as_bytes()).unwrap();
+        assert!(dropped(&mut agent));
+        assert!(rx.recv().is_err());
+
+        let (mut agent, rx) = connect(yaml);
+        agent.write_all(line().as_bytes()).unwrap();
+        assert!(dropped(&mut agent));
+        assert!(rx.recv().is_err());
+    }
+
+    // A curated monitor measurement
+    fn mon(cluster: &str, hostname: &str, sec: i64) -> Metric {
+        let mut metric = Metric::new("mon_daemon");
+        metric.timestamp.sec = sec;
+        metric.add_tag("cluster", cluster);
+        metric.add_tag("hostname", hostname);
+        metric.add_field("used", Value::Integer(1024));
+        metric.add_field("monitors_quorum", Value::Integer(3));
+        metric
+    }
+
+    // One from a full perf dump, with counters of the monitor's own
+    fn full_mon(cluster: &str, hostname: &str, sec: i64) -> Metric {
+        let mut metric = mon(cluster, hostname, sec);
+        metric.fields.clear();
+        metric.add_field("cluster_osd_bytes", Value::Integer(1024));
+        metric.add_counter("cluster_num_object", Value::Integer(88));
+        metric.add_counter("paxos_commit", Value::Integer(45));
+        metric.add_field("paxos_commit_latency_avg", Value::Float(0.125));
+        metric
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let mut dedupe = super::Dedupe::new();
+        assert!(dedupe.keep(&mut mon("ceph", "mon-1", 1458000000), 5));
+        // The same figures from the other monitors
+        assert!(!dedupe.keep(&mut mon("ceph", "mon-2", 1458000001), 5));
+        assert!(!dedupe.keep(&mut mon("ceph", "mon-3", 1458000004), 5));
+        // Another cluster, or the next round
+        assert!(dedupe.keep(&mut mon("backup", "mon-2", 1458000001), 5));
+        assert!(dedupe.keep(&mut mon("ceph", "mon-2", 1458000005), 5));
+        // Only monitor measurements are cluster wide
+        let mut osd = Metric::new("osd_daemon");
+        osd.add_field("used", Value::Integer(1024));
+        assert!(dedupe.keep(&mut osd, 5));
+        assert!(dedupe.keep(&mut osd, 5));
+        assert!(osd.fields.contains_key("used"));
+    }
+
+    #[test]
+    fn test_dedupe_full() {
+        let mut dedupe = super::Dedupe::new();
+        let mut first = full_mon("ceph", "mon-1", 1458000000);
+        assert!(dedupe.keep(&mut first, 5));
+        assert_eq!(first.fields.len(), 4);
+
+        // The other monitors' own counters are kept, without the cluster's
+        let mut second = full_mon("ceph", "mon-2", 1458000001);
+        assert!(dedupe.keep(&mut second, 5));
+        assert_eq!(second.fields.keys().collect::<Vec<_>>(), vec!["paxos_commit", "paxos_commit_latency_avg"]);
+        assert_eq!(second.counters.iter().collect::<Vec<_>>(), vec!["paxos_commit"]);
+
+        let mut next = full_mon("ceph", "mon-2", 1458000005);
+        assert!(dedupe.keep(&mut next, 5));
+        assert_eq!(next.fields.len(), 4);
+    }
+}
+
+/// Listen for agents' `forward` output and pass what they send into the
+/// pipeline, as though it had been collected here
+pub fn initialize_listener(shared: &Arc<SharedArgs>, metrics: Sender<Metric>) -> Result<(), String> {
+    let listen = shared.current().aggregator.listen;
+    let listener = try!(TcpListener::bind(&listen[..]).map_err(|e| format!("{}: {}", listen, e)));
+    info!("Listening for agents on {}", listen);
+    let shared = shared.clone();
+    let dedupe = Arc::new(Mutex::new(Dedupe::new()));
+    let agents = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let max_agents = shared.current().aggregator.max_agents as usize;
+                    if agents.load(Ordering::SeqCst) >= max_agents {
+                        warn!("Turning an agent away, {} are already connected", max_agents);
+                        continue;
+                    }
+                    let agent = Agent::new(&agents);
+                    let shared = shared.clone();
+                    let metrics = metrics.clone();
+                    let dedupe = dedupe.clone();
+                    thread::spawn(move || {
+                        let _agent = agent;
+                        receive(stream, shared, metrics, dedupe)
+                    });
+                },
+                Err(e) => debug!("Unable to accept an agent's connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Counts an agent as connected for as long as it's alive
+struct Agent(Arc<AtomicUsize>);
+
+impl Agent {
+    fn new(agents: &Arc<AtomicUsize>) -> Agent {
+        agents.fetch_add(1, Ordering::SeqCst);
+        Agent(agents.clone())
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// One agent's measurements, a JSON object per line, after an `AUTH` line
+// with the token if there is one
+fn receive(stream: TcpStream, shared: Arc<SharedArgs>, metrics: Sender<Metric>, dedupe: Arc<Mutex<Dedupe>>) {
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or("unknown".to_string());
+    debug!("Agent {} connected", peer);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(IDLE_SECS)));
+    let mut generation = shared.generation();
+    let args = shared.current();
+    let mut dedupe_secs = args.aggregator.dedupe_secs;
+    let mut token = args.aggregator.token;
+    let mut reader = BufReader::new(stream);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match (&mut reader).take(MAX_LINE_BYTES).read_until(b'\n', &mut buf) {
+            Ok(0) => break,
+            Ok(_) => {},
+            Err(e) => {
+                debug!("Dropping agent {}: {}", peer, e);
+                return;
+            }
+        }
+        if buf.len() as u64 == MAX_LINE_BYTES && buf.last() != Some(&b'\n') {
+            warn!("Dropping agent {}: a line is longer than {} bytes", peer, MAX_LINE_BYTES);
+            return;
+        }
+        let line = match str::from_utf8(&buf) {
+            Ok(line) => line.trim(),
+            Err(e) => {
+                debug!("Ignoring a measurement from {}: {}", peer, e);
+                continue;
+            }
+        };
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(expected) = token.take() {
+            let matched = line.starts_with(AUTH_PREFIX) &&
+                          same_bytes(line[AUTH_PREFIX.len()..].as_bytes(), expected.expose().as_bytes());
+            if !matched {
+                warn!("Dropping agent {}: it didn't send the token", peer);
+                return;
+            }
+            continue;
+        }
+        // From an agent with a token for an aggregator without one
+        if line.starts_with(AUTH_PREFIX) {
+            continue;
+        }
+        let mut metric = match Metric::from_forward_json(line) {
+            Ok(metric) => metric,
+            Err(e) => {
+                debug!("Ignoring a measurement from {}: {}", peer, e);
+                continue;
+            }
+        };
+        if shared.generation() != generation {
+            generation = shared.generation();
+            dedupe_secs = shared.current().aggregator.dedupe_secs;
+        }
+        if !dedupe.lock().unwrap().keep(&mut metric, dedupe_secs) {
+            continue;
+        }
+        if metrics.send(metric).is_err() {
+            return;
+        }
+    }
+    debug!("Agent {} disconnected", peer);
+}
+
+fn is_cluster_field(key: &str) -> bool {
+    key.starts_with(CLUSTER_PREFIX) || CLUSTER_FIELDS.contains(&key)
+}
+
+// Compares every byte whatever the first difference, so how long it takes
+// says nothing about how much of a guessed token was right
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Every monitor of a cluster reports the same cluster wide figures, so
+/// they're only kept from the first `mon_daemon` measurement per cluster in
+/// each window.  The others keep the fields of their own monitor, and are
+/// dropped if they have none.
+struct Dedupe {
+    // When each cluster's last kept monitor measurement was taken
+    last: HashMap<String, i64>,
+}
+
+impl Dedupe {
+    fn new() -> Dedupe {
+        Dedupe { last: HashMap::new() }
+    }
+
+    fn keep(&mut self, metric: &mut Metric, window_secs: u64) -> bool {
+        if metric.name != "mon_daemon" {
+            return true;
+        }
+        let cluster = metric.tags.get("cluster").cloned().unwrap_or(String::new());
+        let sec = metric.timestamp.sec;
+        match self.last.get(&cluster) {
+            // Taken within the window of the last one kept, or before it
+            Some(&last) if sec < last + window_secs as i64 => {
+                let shared: Vec<String> = metric.fields.keys().filter(|key| is_cluster_field(key)).cloned().collect();
+                for key in shared.iter() {
+                    metric.fields.remove(key);
+                    metric.counters.remove(key);
+                }
+                return !metric.fields.is_empty();
+            },
+            _ => {},
+        }
+        self.last.insert(cluster, sec);
+        true
+    }
+}
@@ -1,110 +1,107 @@
-use std::fs;
-use std::io;
 use std::path::Path;
-use std::sync::mpsc::{Receiver, channel};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, channel};
 use std::thread;
 use std::time::Duration;
 
-use Args;
-use ceph::*;
+use admin_socket;
+use config::{Ceph, PerfDump, SharedArgs};
+use discovery;
+use metric::Metric;
+use perf_dump::{self, Schemas};
+use rates::Rates;
 
-use influent::create_client;
-use influent::client::Credentials;
-
-pub fn initialize_monitor_scanner(args: &Args) {
-    let args = args.clone();
+pub fn initialize_monitor_scanner(shared: &Arc<SharedArgs>, metrics: Sender<Metric>) {
+    let shared = shared.clone();
     thread::spawn(move || {
         // let _ = log_queue.send(LogMessage::new(LogType::TestMessage, "{\"msg\": \"New thread with send queue for Ceph Monitor checking\"}".to_string() ));
         debug!("Monitor thread active");
-        let periodic = timer_periodic(5000);
-
-        let mut is_monitor = check_is_monitor();
 
         let mut i = 0;
-        let do_influx = args.influx.is_some() && args.outputs.contains(&"influx".to_string());
-        let mut user = String::new();
-        let mut password = String::new();
-        let credentials: Credentials;
-        let host: String;
-        let mut hosts: Vec<&str> = vec![];
-
-        let influx = args.influx;
-        let client = if do_influx {
-            let influx = influx.unwrap();
-            user = influx.user.clone();
-            password = influx.password.clone();
-            credentials = Credentials {
-                username: &user[..],
-                password: &password[..],
-                database: "ceph"
-            };
-            host = format!("http://{}:{}", influx.host, influx.port);
-            hosts = vec![&host[..]];
-            create_client(credentials, hosts)
-        } else {
-            credentials = Credentials {
-                username: &user[..],
-                password: &password[..],
-                database: "",
-            };
-            create_client(credentials, hosts)
-        };
-
         loop {
-            trace!("Going around Monitor loop again!");
-            i = i + 1;
-            if is_monitor {
-                trace!("Getting MON info");
-                match get_monitor_perf_dump_raw() {
-                    Some(dump) => {
-                        // let _ = log_queue.send(LogMessage::new(LogType::CephDaemonMonMessage, dump));
-                        if do_influx {
-                            influx::log_to_influx(&dump, &client, &args.hostname);
-                        }
-                    },
-                    None => {
-                        is_monitor = check_is_monitor();
-                    }
-                };
+            // Everything below is rebuilt whenever the config is reloaded
+            let generation = shared.generation();
+            let args = shared.current();
+            let settings = args.collectors.monitor.clone();
+            if !settings.enabled {
+                debug!("Monitor collector disabled");
+                shared.wait_for_reload(generation);
+                continue;
             }
-            is_monitor = match i % 10 {
-                0 => check_is_monitor(),
-                _ => is_monitor,
-            };
-            let _ = periodic.recv();
-        }
-    });
-}
+            let periodic = timer_periodic(settings.interval_ms);
+            let clusters = args.clusters.clone();
+            let mut monitors: Vec<Option<String>> = clusters.iter().map(check_is_monitor).collect();
+            let mut schemas = Schemas::new();
+            let mut rates = Rates::new();
 
-fn has_child_directory(dir: &Path) -> Result<bool, io::Error> {
-    if try!(fs::metadata(dir)).is_dir() {
-        for entry in try!(fs::read_dir(dir)) {
-            let entry = try!(entry);
-            if try!(fs::metadata(entry.path())).is_dir() {
-                return Ok(true);
+            while shared.generation() == generation {
+                trace!("Going around Monitor loop again!");
+                i = i + 1;
+                for (ceph, monitor) in clusters.iter().zip(monitors.iter_mut()) {
+                    if let Some(mon_id) = monitor.clone() {
+                        trace!("Getting MON info for {}", ceph.cluster);
+                        let socket = ceph.expand(&ceph.admin_socket, "mon", &mon_id);
+                        match admin_socket::perf_dump(Path::new(&socket)) {
+                            Ok(dump) => {
+                                let metric = match settings.perf_dump {
+                                    PerfDump::Curated => measurement::to_metric(&dump, &args.hostname, &ceph.cluster),
+                                    PerfDump::Full => {
+                                        match schemas.counters(&socket).and_then(|counters| perf_dump::to_metric("mon_daemon", &dump, counters)) {
+                                            Ok(mut metric) => {
+                                                measurement::add_tags(&mut metric, &args.hostname, &ceph.cluster);
+                                                Some(metric)
+                                            },
+                                            Err(e) => {
+                                                debug!("Unable to read the perf counters of {} mon.{}: {}", ceph.cluster, mon_id, e);
+                                                None
+                                            },
+                                        }
+                                    },
+                                };
+                                if let Some(mut metric) = metric {
+                                    if settings.rates {
+                                        rates.add(&socket, &mut metric);
+                                    }
+                                    let _ = metrics.send(metric);
+                                }
+                            },
+                            Err(e) => {
+                                debug!("Unable to get perf dump for {} mon.{}: {}", ceph.cluster, mon_id, e);
+                                schemas.forget(&socket);
+                                rates.forget(&socket);
+                                *monitor = check_is_monitor(ceph);
+                            }
+                        };
+                    }
+                    if i % settings.rediscover_every == 0 {
+                        *monitor = check_is_monitor(ceph);
+                    }
+                }
+                let _ = periodic.recv();
             }
+            debug!("Monitor thread picking up new configuration");
         }
-    }
-    return Ok(false);
+    });
 }
 
-// Look for /var/lib/ceph/mon/ceph-ip-172-31-24-128
-fn check_is_monitor() -> bool {
+// Look for /var/lib/ceph/mon/ceph-ip-172-31-24-128 and give back the
+// monitor's id, ip-172-31-24-128
+fn check_is_monitor(ceph: &Ceph) -> Option<String> {
     // does it have a mon directory entry?
-    match has_child_directory(Path::new("/var/lib/ceph/mon")){
-        Ok(result) => result,
-        Err(_) => {
-            info!("No Monitor found");
-            false
+    match discovery::daemon_ids(ceph, &ceph.mon_data, "mon") {
+        Ok(ref ids) if !ids.is_empty() => Some(ids[0].clone()),
+        _ => {
+            info!("No Monitor found for {}", ceph.cluster);
+            None
         }
     }
 }
 
-fn timer_periodic(ms: u32) -> Receiver<()> {
+fn timer_periodic(ms: u64) -> Receiver<()> {
     let (tx, rx) = channel();
     thread::spawn(move || {
         loop {
-            thread::sleep(Duration::from_millis(ms as u64));
+            thread::sleep(Duration::from_millis(ms));
             if tx.send(()).is_err() {
                 break;
             }
@@ -113,11 +110,9 @@ fn timer_periodic(ms: u32) -> Receiver<()> {
     rx
 }
 
-mod influx {
-    use time;
-    use influent::measurement::{Measurement, Value};
+mod measurement {
+    use metric::{Metric, Value};
     use serde_json;
-    use influent::client::{Precision, Client};
     #[cfg(test)]
     mod tests {
         #[test]
@@ -358,36 +353,34 @@ mod influx {
         }
     }
 
-    pub fn log_to_influx(json: &String, client: &Client, hostname: &str) {
-        match MonMeasurement::from_json(json) {
-            Some(mon_m) => {
-                let mut measurement = Measurement::new("mon_daemon");
-                measurement.set_timestamp(time::now().to_timespec().sec);
-                measurement.add_tag("type", "monitor");
-                measurement.add_tag("hostname", hostname);
-
-                measurement.add_field("used", Value::Integer(mon_m.used as i64));
-                measurement.add_field("avail", Value::Integer(mon_m.avail as i64));
-                measurement.add_field("total", Value::Integer(mon_m.total as i64));
-                measurement.add_field("osds", Value::Integer(mon_m.osds as i64));
-                measurement.add_field("osds_up", Value::Integer(mon_m.osds_up as i64));
-                measurement.add_field("osds_in", Value::Integer(mon_m.osds_in as i64));
-                measurement.add_field("osd_epoch", Value::Integer(mon_m.osd_epoch as i64));
-                measurement.add_field("pgs", Value::Integer(mon_m.pgs as i64));
-                measurement.add_field("pgs_active_clean", Value::Integer(mon_m.pgs_active_clean as i64));
-                measurement.add_field("ppgs_active", Value::Integer(mon_m.ppgs_active as i64));
-                measurement.add_field("pgs_peering", Value::Integer(mon_m.pgs_peering as i64));
-                measurement.add_field("objects", Value::Integer(mon_m.objects as i64));
-                measurement.add_field("objects_degraded", Value::Integer(mon_m.objects_degraded as i64));
-                measurement.add_field("objects_unfound", Value::Integer(mon_m.objects_unfound as i64));
-                measurement.add_field("monitors", Value::Integer(mon_m.monitors as i64));
-                measurement.add_field("monitors_quorum", Value::Integer(mon_m.monitors_quorum as i64));
+    pub fn add_tags(metric: &mut Metric, hostname: &str, cluster: &str) {
+        metric.add_tag("type", "monitor");
+        metric.add_tag("hostname", hostname);
+        metric.add_tag("cluster", cluster);
+    }
 
-                // super::send_messages(vec![measurement], args);
-                let _ = client.write_one(measurement, Some(Precision::Seconds));
-            },
-            None=> {}
-        }
+    pub fn to_metric(json: &String, hostname: &str, cluster: &str) -> Option<Metric> {
+        MonMeasurement::from_json(json).map(|mon_m| {
+            let mut metric = Metric::new("mon_daemon");
+            add_tags(&mut metric, hostname, cluster);
 
+            metric.add_field("used", Value::Integer(mon_m.used as i64));
+            metric.add_field("avail", Value::Integer(mon_m.avail as i64));
+            metric.add_field("total", Value::Integer(mon_m.total as i64));
+            metric.add_field("osds", Value::Integer(mon_m.osds as i64));
+            metric.add_field("osds_up", Value::Integer(mon_m.osds_up as i64));
+            metric.add_field("osds_in", Value::Integer(mon_m.osds_in as i64));
+            metric.add_field("osd_epoch", Value::Integer(mon_m.osd_epoch as i64));
+            metric.add_field("pgs", Value::Integer(mon_m.pgs as i64));
+            metric.add_field("pgs_active_clean", Value::Integer(mon_m.pgs_active_clean as i64));
+            metric.add_field("ppgs_active", Value::Integer(mon_m.ppgs_active as i64));
+            metric.add_field("pgs_peering", Value::Integer(mon_m.pgs_peering as i64));
+            metric.add_field("objects", Value::Integer(mon_m.objects as i64));
+            metric.add_field("objects_degraded", Value::Integer(mon_m.objects_degraded as i64));
+            metric.add_field("objects_unfound", Value::Integer(mon_m.objects_unfound as i64));
+            metric.add_field("monitors", Value::Integer(mon_m.monitors as i64));
+            metric.add_field("monitors_quorum", Value::Integer(mon_m.monitors_quorum as i64));
+            metric
+        })
     }
 }
@@ -1,138 +1,154 @@
-use std::fs;
+use std::fs::File;
 use std::io;
+use std::io::prelude::*;
 use std::path::Path;
 use std::str::FromStr;
-use std::sync::mpsc::{Receiver, channel};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, channel};
 use std::thread;
 use std::time::Duration;
 
-use ceph::{osd_mount_point, get_osd_perf_dump_raw};
-use regex::Regex;
-
-use Args;
-use influent::create_client;
-use influent::client::Credentials;
+use admin_socket;
+use config::{Ceph, PerfDump, SharedArgs};
+use discovery;
+use metric::Metric;
+use perf_dump::{self, Schemas};
+use rates::Rates;
 
 //NOTE: This skips a lot of failure cases
 // Check for osd sockets and give back a vec of osd numbers that are active
-fn get_osds_with_match() -> Result<Vec<u64>, io::Error> {
-    let mut osds: Vec<u64> = Vec::new();
-
-    let osd_regex = Regex::new(r"ceph-osd.(?P<number>\d+).asok").unwrap();
-
-    for entry in try!(fs::read_dir(Path::new("/var/run/ceph"))){
-        //parse the unix socket names such as:
-        //ceph-mon.ip-172-31-22-89.asok
-        //ceph-osd.1.asok
-
-        let entry = try!(entry);
-        let sock_addr_osstr = entry.file_name();
-        let file_name = match sock_addr_osstr.to_str(){
-            Some(name) => name,
-            None => {
-                //Skip files we can't turn into a string
-                continue;
-            }
-        };
-
-        //Ignore failures
-        match osd_regex.captures(file_name){
-            Some(osd) => {
-                if let Some(osd_number) = osd.name("number"){
-                    let num = u64::from_str(osd_number).unwrap();
-                    osds.push(num);
-                }
-                //Ignore failures
-            }
-            //Ignore non matches, ie: ceph monitors
-            None => {},
-        }
-    }
+fn get_osds_with_match(ceph: &Ceph) -> Result<Vec<u64>, io::Error> {
+    //parse the unix socket names such as:
+    //ceph-mon.ip-172-31-22-89.asok
+    //ceph-osd.1.asok
+    let ids = try!(discovery::daemon_ids(ceph, &ceph.admin_socket, "osd"));
+    //Ignore anything that isn't an osd number
+    let mut osds: Vec<u64> = ids.iter().filter_map(|id| u64::from_str(id).ok()).collect();
+    osds.sort();
     return Ok(osds);
 }
 
-fn get_osds() -> Vec<u64> {
-    match get_osds_with_match() {
+fn get_osds(ceph: &Ceph) -> Vec<u64> {
+    match get_osds_with_match(ceph) {
         Ok(list) => list,
         Err(_) => {
-            info!("No OSDs found");
+            info!("No OSDs found for {}", ceph.cluster);
             vec![]
         }
     }
 }
 
-pub fn initialize_osd_scanner(args: &Args) {
-    let args = args.clone();
+// Find the device mounted on the OSD's data directory, ie: /dev/sdb1
+fn osd_mount_point(ceph: &Ceph, osd_num: &u64) -> Option<String> {
+    let data_dir = ceph.expand(&ceph.osd_data, "osd", &osd_num.to_string());
+    let mut mounts = String::new();
+    match File::open("/proc/mounts").and_then(|mut f| f.read_to_string(&mut mounts)) {
+        Ok(_) => {},
+        Err(e) => {
+            debug!("Unable to read /proc/mounts: {}", e);
+            return None;
+        }
+    }
+    for line in mounts.lines() {
+        let mut parts = line.split_whitespace();
+        if let (Some(device), Some(mount_point)) = (parts.next(), parts.next()) {
+            if Path::new(mount_point) == Path::new(&data_dir) {
+                return Some(device.to_string());
+            }
+        }
+    }
+    None
+}
+
+pub fn initialize_osd_scanner(shared: &Arc<SharedArgs>, metrics: Sender<Metric>) {
+    let shared = shared.clone();
 
     thread::spawn(move || {
-        let do_influx = args.influx.is_some() && args.outputs.contains(&"influx".to_string());
-        let mut user: String = String::new();
-        let mut password: String = String::new();
-        let credentials: Credentials;
-        let host: String;
-        let mut hosts: Vec<&str> = vec![];
-
-        let influx = args.influx;
-        let client = if do_influx {
-            let influx = influx.unwrap();
-            user = influx.user.clone();
-            password = influx.password.clone();
-            credentials = Credentials {
-                username: &user[..],
-                password: &password[..],
-                database: "ceph"
-            };
-            host = format!("http://{}:{}", influx.host, influx.port);
-            hosts = vec![&host[..]];
-            create_client(credentials, hosts)
-        } else {
-            credentials = Credentials {
-                username: &user[..],
-                password: &password[..],
-                database: "",
-            };
-            create_client(credentials, hosts)
-        };
         // let _ = log_queue.send(LogMessage::new(LogType::TestMessage, "{\"msg\": \"New thread with send queue for Ceph Monitor checking\"}".to_string() ));
         debug!("OSD thread active");
-        let periodic = timer_periodic(5000);
 
-        let mut osd_list = get_osds();
-        debug!("OSDs on this host: {:?}", osd_list);
         let mut i = 0;
         loop {
-            trace!("Going around OSD loop again!");
-            i = i + 1;
-            for osd_num in osd_list.clone().iter(){
-                // match ceph::get_osd_perf_dump(osd_num) {
-                match get_osd_perf_dump_raw(osd_num) {
-                    Some(osd) => {
-                        let drive_name = osd_mount_point(osd_num).unwrap_or("".to_string());
-                        // logging::osd_perf::log(osd, &args, *osd_num, &drive_name);
-                        // logging::json::log_osd(osd, &args, *osd_num, &drive_name);
-                        // let _ = log_queue.send(LogMessage{ log_type: LogType::CephDaemonOsdMessage, json_body: osd, osd_num: Some(*osd_num), drive_name: Some(drive_name)});
-                        let osd_num = format!("{}", osd_num);
-                        if do_influx {
-                            influx::send_to_influx(&osd, &client, &args.hostname, &drive_name[..], &osd_num[..]);
+            // Everything below is rebuilt whenever the config is reloaded
+            let generation = shared.generation();
+            let args = shared.current();
+            let settings = args.collectors.osd.clone();
+            if !settings.enabled {
+                debug!("OSD collector disabled");
+                shared.wait_for_reload(generation);
+                continue;
+            }
+            let periodic = timer_periodic(settings.interval_ms);
+            let clusters = args.clusters.clone();
+            let mut osd_lists: Vec<Vec<u64>> = clusters.iter().map(get_osds).collect();
+            debug!("OSDs on this host: {:?}", osd_lists);
+            let mut schemas = Schemas::new();
+            let mut rates = Rates::new();
+            // Only what needs them gets the extra fields
+            let latency_counts = settings.rates || args.outputs.iter().any(|o| o == "otlp");
+
+            while shared.generation() == generation {
+                trace!("Going around OSD loop again!");
+                i = i + 1;
+                for (ceph, osd_list) in clusters.iter().zip(osd_lists.iter_mut()) {
+                    for osd_num in osd_list.iter(){
+                        // match ceph::get_osd_perf_dump(osd_num) {
+                        let socket = ceph.expand(&ceph.admin_socket, "osd", &osd_num.to_string());
+                        match admin_socket::perf_dump(Path::new(&socket)) {
+                            Ok(osd) => {
+                                let drive_name = osd_mount_point(ceph, osd_num).unwrap_or("".to_string());
+                                // logging::osd_perf::log(osd, &args, *osd_num, &drive_name);
+                                // logging::json::log_osd(osd, &args, *osd_num, &drive_name);
+                                // let _ = log_queue.send(LogMessage{ log_type: LogType::CephDaemonOsdMessage, json_body: osd, osd_num: Some(*osd_num), drive_name: Some(drive_name)});
+                                let osd_num = format!("{}", osd_num);
+                                let metric = match settings.perf_dump {
+                                    PerfDump::Curated => {
+                                        measurement::to_metric(&osd, &args.hostname, &ceph.cluster, &drive_name[..], &osd_num[..], latency_counts)
+                                    },
+                                    PerfDump::Full => {
+                                        match schemas.counters(&socket).and_then(|counters| perf_dump::to_metric("osd_daemon", &osd, counters)) {
+                                            Ok(mut metric) => {
+                                                measurement::add_tags(&mut metric, &args.hostname, &ceph.cluster, &drive_name[..], &osd_num[..]);
+                                                Some(metric)
+                                            },
+                                            Err(e) => {
+                                                debug!("Unable to read the perf counters of {} osd.{}: {}", ceph.cluster, osd_num, e);
+                                                None
+                                            },
+                                        }
+                                    },
+                                };
+                                if let Some(mut metric) = metric {
+                                    if settings.rates {
+                                        rates.add(&socket, &mut metric);
+                                    }
+                                    let _ = metrics.send(metric);
+                                }
+                            },
+                            Err(e) => {
+                                debug!("Unable to get perf dump for {} osd.{}: {}", ceph.cluster, osd_num, e);
+                                schemas.forget(&socket);
+                                rates.forget(&socket);
+                                continue;
+                            },
                         }
-                    },
-                    None => continue,
+                    }
+                    if i % settings.rediscover_every == 0 {
+                        *osd_list = get_osds(ceph);
+                    }
                 }
+                let _ = periodic.recv();
             }
-            osd_list = match i % 10 {
-                0 => get_osds(),
-                _ => osd_list,
-            };
-            let _ = periodic.recv();
+            debug!("OSD thread picking up new configuration");
         }
     });
 }
 
-fn timer_periodic(ms: u32) -> Receiver<()> {
+fn timer_periodic(ms: u64) -> Receiver<()> {
     let (tx, rx) = channel();
     thread::spawn(move || {
         loop {
-            thread::sleep(Duration::from_millis(ms as u64));
+            thread::sleep(Duration::from_millis(ms));
             if tx.send(()).is_err() {
                 break;
             }
@@ -141,10 +157,8 @@ fn timer_periodic(ms: u32) -> Receiver<()> {
     rx
 }
 
-mod influx {
-    use time;
-    use influent::measurement::{Measurement, Value};
-    use influent::client::{Precision, Client};
+mod measurement {
+    use metric::{Metric, Value};
     use serde_json;
 
     macro_rules! find_u64 {
@@ -191,6 +205,14 @@ mod influx {
                 let measurement = super::OsdMeasurement::from_json(&json).unwrap();
 
                 assert_eq!(measurement.ops, 4889);
+                assert_eq!(measurement.latency_counts[0], ("op_latency_count", 642));
+
+                // Only sent when something needs them
+                let metric = super::to_metric(&json, "ceph-1", "ceph", "sdb", "3", false).unwrap();
+                assert!(metric.is_counter("op_latency"));
+                assert!(!metric.fields.contains_key("op_latency_count"));
+                let metric = super::to_metric(&json, "ceph-1", "ceph", "sdb", "3", true).unwrap();
+                assert!(metric.is_counter("op_latency_count"));
         }
     }
 
@@ -209,6 +231,8 @@ mod influx {
         apply_latency: f64,
         commit_latency: f64,
         queue_transaction_latency_avg: f64,
+        // How many operations each latency sum covers
+        latency_counts: Vec<(&'static str, u64)>,
         ops: u64,
     }
 
@@ -239,6 +263,17 @@ mod influx {
                         apply_latency: find_f64!(s, "filestore.apply_latency.sum"),
                         commit_latency: find_f64!(s, "filestore.commit_latency.sum"),
                         queue_transaction_latency_avg: find_f64!(s, "filestore.queue_transaction_latency_avg.sum"),
+                        latency_counts: vec![
+                            ("op_latency_count", find_u64!(s, "osd.op_latency.avgcount")),
+                            ("op_r_latency_count", find_u64!(s, "osd.op_r_latency.avgcount")),
+                            ("op_w_latency_count", find_u64!(s, "osd.op_w_latency.avgcount")),
+                            ("subop_latency_count", find_u64!(s, "osd.subop_latency.avgcount")),
+                            ("subop_w_latency_count", find_u64!(s, "osd.subop_w_latency.avgcount")),
+                            ("journal_latency_count", find_u64!(s, "filestore.journal_latency.avgcount")),
+                            ("apply_latency_count", find_u64!(s, "filestore.apply_latency.avgcount")),
+                            ("commit_latency_count", find_u64!(s, "filestore.commit_latency.avgcount")),
+                            ("queue_transaction_latency_avg_count", find_u64!(s, "filestore.queue_transaction_latency_avg.avgcount")),
+                        ],
                         ops: find_u64!(s, "filestore.ops"),
                     })
                 },
@@ -247,36 +282,44 @@ mod influx {
         }
     }
 
-    pub fn send_to_influx(json: &String, client: &Client, hostname: &str, drive_name: &str, osd_num: &str) {
-        match OsdMeasurement::from_json(json) {
-            Some(osd_m) => {
-                let mut measurement = Measurement::new("osd_daemon");
-                measurement.add_tag("type", "osd");
-                measurement.set_timestamp(time::now().to_timespec().sec);
-                measurement.add_tag("hostname", hostname);
-                measurement.add_tag("osd_num", osd_num);
-                measurement.add_tag("drive_name", drive_name);
-
-                measurement.add_field("load_average", Value::Integer(osd_m.load_average as i64));
-                measurement.add_field("queued_ops", Value::Integer(osd_m.queued_ops as i64));
-                measurement.add_field("stat_bytes", Value::Integer(osd_m.stat_bytes as i64));
-                measurement.add_field("stat_bytes_used", Value::Integer(osd_m.stat_bytes_used as i64));
-                measurement.add_field("stat_bytes_avail", Value::Integer(osd_m.stat_bytes_avail as i64));
-                measurement.add_field("op_latency", Value::Float(osd_m.op_latency as f64));
-                measurement.add_field("op_r_latency", Value::Float(osd_m.op_r_latency as f64));
-                measurement.add_field("op_w_latency", Value::Float(osd_m.op_w_latency as f64));
-                measurement.add_field("subop_latency", Value::Float(osd_m.subop_latency as f64));
-                measurement.add_field("subop_w_latency", Value::Float(osd_m.subop_w_latency as f64));
-                measurement.add_field("journal_latency", Value::Float(osd_m.journal_latency as f64));
-                measurement.add_field("apply_latency", Value::Float(osd_m.apply_latency as f64));
-                measurement.add_field("commit_latency", Value::Float(osd_m.commit_latency as f64));
-                measurement.add_field("queue_transaction_latency_avg", Value::Float(osd_m.queue_transaction_latency_avg as f64));
-                measurement.add_field("ops", Value::Integer(osd_m.ops as i64));
+    pub fn add_tags(metric: &mut Metric, hostname: &str, cluster: &str, drive_name: &str, osd_num: &str) {
+        metric.add_tag("type", "osd");
+        metric.add_tag("hostname", hostname);
+        metric.add_tag("cluster", cluster);
+        metric.add_tag("osd_num", osd_num);
+        metric.add_tag("drive_name", drive_name);
+    }
 
-                let _ = client.write_one(measurement, Some(Precision::Seconds));
-            },
-            None=> {}
-        };
+    /// The curated OSD measurement, with each latency's `_count` as well if
+    /// `latency_counts`, for rates and histograms
+    pub fn to_metric(json: &String, hostname: &str, cluster: &str, drive_name: &str, osd_num: &str,
+                     latency_counts: bool) -> Option<Metric> {
+        OsdMeasurement::from_json(json).map(|osd_m| {
+            let mut metric = Metric::new("osd_daemon");
+            add_tags(&mut metric, hostname, cluster, drive_name, osd_num);
 
+            metric.add_field("load_average", Value::Integer(osd_m.load_average as i64));
+            metric.add_field("queued_ops", Value::Integer(osd_m.queued_ops as i64));
+            metric.add_field("stat_bytes", Value::Integer(osd_m.stat_bytes as i64));
+            metric.add_field("stat_bytes_used", Value::Integer(osd_m.stat_bytes_used as i64));
+            metric.add_field("stat_bytes_avail", Value::Integer(osd_m.stat_bytes_avail as i64));
+            // Latencies are the total seconds spent since the OSD started
+            metric.add_counter("op_latency", Value::Float(osd_m.op_latency));
+            metric.add_counter("op_r_latency", Value::Float(osd_m.op_r_latency));
+            metric.add_counter("op_w_latency", Value::Float(osd_m.op_w_latency));
+            metric.add_counter("subop_latency", Value::Float(osd_m.subop_latency));
+            metric.add_counter("subop_w_latency", Value::Float(osd_m.subop_w_latency));
+            metric.add_counter("journal_latency", Value::Float(osd_m.journal_latency));
+            metric.add_counter("apply_latency", Value::Float(osd_m.apply_latency));
+            metric.add_counter("commit_latency", Value::Float(osd_m.commit_latency));
+            metric.add_counter("queue_transaction_latency_avg", Value::Float(osd_m.queue_transaction_latency_avg));
+            if latency_counts {
+                for &(name, count) in osd_m.latency_counts.iter() {
+                    metric.add_counter(name, Value::Integer(count as i64));
+                }
+            }
+            metric.add_counter("ops", Value::Integer(osd_m.ops as i64));
+            metric
+        })
     }
 }
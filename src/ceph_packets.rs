@@ -1,81 +1,84 @@
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
 use std::thread;
 
 use ceph::sniffer::*;
 use ceph::sniffer::serial::CephMessageWithHeader;
-use pcap::{Capture, Device};
+use pcap::{Active, Capture, Device};
 
-use Args;
+use config::SharedArgs;
+use metric::{Metric, Value};
 
-use influent::measurement::{Measurement, Value};
-use influent::create_client;
-use influent::client::{Credentials, Precision, Client};
-
-use time;
-
-pub fn initialize_pcap(args: &Args) {
-    let args = args.clone();
+pub fn initialize_pcap(shared: &Arc<SharedArgs>, metrics: Sender<Metric>) {
+    let shared = shared.clone();
     thread::spawn(move || {
         // let _ = log_queue.send(LogMessage::new(LogType::TestMessage, "{\"msg\": \"New thread with send queue for Ceph packet sniffing\"}".to_string() ));
         debug!("Packet Sniffing thread active");
-        let hostname: &str = &args.hostname[..];
-        let device = match any_device() {
-            Some(dev) => dev,
-            None => return,
-        };
-        let device_name = device.name.clone();
-
-        let mut cap = Capture::from_device(device).unwrap() //open the device
-                              .promisc(false)
-                              //.snaplen(500) //Might need this still if we're losing packets
-                              .timeout(100)
-                              .open() //activate the handle
-                              .unwrap(); //assume activation worked
-
-        debug!("Setting up filter({})", &device_name);
-        //Grab both monitor and OSD traffic
-        match cap.filter("tcp dst portrange 6789-7300"){
-            Ok(_) => {
-                debug!("Filter successful({})", &device_name);
-            },
-            Err(e) => {
-                error!("Invalid capture filter({}). Error: {:?}", &device_name, e);
-                return;
+        loop {
+            let generation = shared.generation();
+            if !shared.current().collectors.packets.enabled {
+                debug!("Packet collector disabled");
+                shared.wait_for_reload(generation);
+                continue;
             }
+            let mut cap = match open_capture() {
+                Some(cap) => cap,
+                None => return,
+            };
+            sniff(&shared, &mut cap, &metrics);
+            debug!("Packet collector disabled, closing the capture");
         }
-        debug!("Waiting for packets({})", &device_name);
-        //Grab some packets :)
-        let mut user = String::new();
-        let mut password = String::new();
-        let credentials: Credentials;
-        let host: String;
-        let mut hosts: Vec<&str> = vec![];
+    });
+}
 
-        let do_influx = args.influx.is_some() && args.outputs.contains(&"influx".to_string());
-        let influx = args.influx;
-        let client = if do_influx {
-            let influx = influx.unwrap();
-            user = influx.user.clone();
-            password = influx.password.clone();
-            credentials = Credentials {
-                username: &user[..],
-                password: &password[..],
-                database: "ceph"
-            };
-            host = format!("http://{}:{}", influx.host, influx.port);
-            hosts = vec![&host[..]];
-            create_client(credentials, hosts)
-        } else {
-            credentials = Credentials {
-                username: &user[..],
-                password: &password[..],
-                database: "",
-            };
-            create_client(credentials, hosts)
-        };
-        //Infinite loop
+fn open_capture() -> Option<Capture<Active>> {
+    let device = match any_device() {
+        Some(dev) => dev,
+        None => return None,
+    };
+    let device_name = device.name.clone();
+
+    let mut cap = Capture::from_device(device).unwrap() //open the device
+                          .promisc(false)
+                          //.snaplen(500) //Might need this still if we're losing packets
+                          .timeout(100)
+                          .open() //activate the handle
+                          .unwrap(); //assume activation worked
+
+    debug!("Setting up filter({})", &device_name);
+    //Grab both monitor and OSD traffic
+    match cap.filter("tcp dst portrange 6789-7300"){
+        Ok(_) => {
+            debug!("Filter successful({})", &device_name);
+        },
+        Err(e) => {
+            error!("Invalid capture filter({}). Error: {:?}", &device_name, e);
+            return None;
+        }
+    }
+    debug!("Waiting for packets({})", &device_name);
+    Some(cap)
+}
 
+// Log packets until the packet collector is disabled
+fn sniff(shared: &Arc<SharedArgs>, cap: &mut Capture<Active>, metrics: &Sender<Metric>) {
+    //Grab some packets :)
+    loop {
+        // The capture stays open, only the tags are picked up again when the
+        // config is reloaded
+        let generation = shared.generation();
+        let args = shared.current();
+        if !args.collectors.packets.enabled {
+            return;
+        }
+        let hostname: &str = &args.hostname[..];
+        // Packets can only be put down to a cluster when there's just one
+        let cluster = match args.clusters.len() {
+            1 => Some(&args.clusters[0].cluster[..]),
+            _ => None,
+        };
 
-        loop {
+        while shared.generation() == generation {
             match cap.next(){
                 //We received a packet
                 Ok(packet) =>{
@@ -96,8 +99,8 @@ pub fn initialize_pcap(args: &Args) {
                                     //     drive_name: None,
                                     //     ceph_msg: Some(result),
                                     // });
-                                    if do_influx {
-                                        log_to_influx(result, &client, hostname);
+                                    if let Some(metric) = to_metric(result, hostname, cluster) {
+                                        let _ = metrics.send(metric);
                                     }
                                 },
                                 //TODO: What should we do here?
@@ -115,39 +118,40 @@ pub fn initialize_pcap(args: &Args) {
                 Err(_) => {},
             }
         }
-    });
+        debug!("Packet Sniffing thread picking up new configuration");
+    }
 }
 
-fn log_to_influx(msg: CephMessageWithHeader, client: &Client, hostname: &str) {
+fn to_metric(msg: CephMessageWithHeader, hostname: &str, cluster: Option<&str>) -> Option<Metric> {
     match msg.ceph_message.message {
         serial::Message::OsdOp(ref osd_op) => {
             let flags = format!("{:?}", osd_op.flags);
             let src_addr = msg.header.src_addr.ip_address();
             let dst_addr = msg.header.dst_addr.ip_address();
-            let mut measurement = Measurement::new("osd_operation");
-            measurement.set_timestamp(time::now().to_timespec().sec);
-            measurement.add_tag("type", "osd_op");
-            measurement.add_tag("hostname", hostname);
-            measurement.add_tag("src_ip", &src_addr[..]);
-            measurement.add_tag("dst_ip", &dst_addr[..]);
+            let mut metric = Metric::new("osd_operation");
+            metric.add_tag("type", "osd_op");
+            metric.add_tag("hostname", hostname);
+            if let Some(cluster) = cluster {
+                metric.add_tag("cluster", cluster);
+            }
+            metric.add_tag("src_ip", &src_addr[..]);
+            metric.add_tag("dst_ip", &dst_addr[..]);
 
-            measurement.add_field("size", Value::Integer(osd_op.operation.payload_size as i64));
-            measurement.add_field("operation", Value::String(&flags[..]));
-            measurement.add_field("count", Value::Integer(osd_op.operation_count as i64));
+            metric.add_field("size", Value::Integer(osd_op.operation.payload_size as i64));
+            metric.add_field("operation", Value::String(flags));
+            metric.add_field("count", Value::Integer(osd_op.operation_count as i64));
 
             if osd_op.flags.contains(serial::CEPH_OSD_FLAG_WRITE) {
-                measurement.add_tag("type", "write");
+                metric.add_tag("type", "write");
             } else if osd_op.flags.contains(serial::CEPH_OSD_FLAG_READ) {
-                measurement.add_tag("type", "read");
+                metric.add_tag("type", "read");
             } else {
                 trace!("{:?} doesn't contain {:?}", osd_op.flags, vec![serial::CEPH_OSD_FLAG_WRITE, serial::CEPH_OSD_FLAG_READ]);
             }
-            let _ = client.write_one(measurement, Some(Precision::Seconds));
+            Some(metric)
         },
-        _ => {}
+        _ => None
     }
-    // debug!("About to log {:?}", measurements);
-    // let _ = client.write_many(measurements, Some(Precision::Seconds));
 }
 
 fn any_device() -> Option<Device> {
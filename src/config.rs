@@ -0,0 +1,2313 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use hostname;
+use log::LogLevel;
+use metric::Precision;
+use regex::Regex;
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+
+pub const DEFAULT_CONFIG_PATH: &'static str = "/etc/default/decode_ceph.yaml";
+
+// Printed in place of passwords and other secrets
+const REDACTED: &'static str = "<redacted>";
+
+// Tags that collectors set themselves, so they can't be used in `tags:`
+const RESERVED_TAGS: &'static [&'static str] = &["type", "hostname", "cluster", "osd_num", "drive_name", "src_ip", "dst_ip"];
+
+// Graphite path for measurements without a template of their own
+const DEFAULT_GRAPHITE_TEMPLATE: &'static str = "ceph.{hostname}.{measurement}";
+
+// Every output name that `outputs:` may contain
+const OUTPUTS: &'static [&'static str] = &["stdout", "influx", "influx_udp", "prometheus", "graphite", "statsd",
+                                            "opentsdb", "file", "forward", "otlp"];
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevel;
+    #[test]
+    fn test_parse_file() {
+        let file = r#"
+outputs:
+  - stdout
+  - influx
+influx:
+  host: 127.0.0.1
+  port: 8086
+  user: root
+  password: root
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+
+        assert_eq!(args.outputs, vec!["stdout", "influx"]);
+        assert_eq!(args.influx.unwrap().port, 8086);
+        assert_eq!(args.config_path, "test.yaml");
+    }
+
+    #[test]
+    fn test_parse_unknown_key() {
+        let file = r#"
+outputs:
+  - influx
+influx:
+  host: 127.0.0.1
+  prot: 8086
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+
+        assert_eq!(err.key, Some("influx.prot".to_string()));
+        assert_eq!(err.line, Some(6));
+    }
+
+    #[test]
+    fn test_parse_bad_types() {
+        let file = r#"
+outputs:
+  - influx
+influx:
+  port: "8086"
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("influx.port".to_string()));
+
+        let err = super::parse("outputs: influx", "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("outputs".to_string()));
+
+        let err = super::parse("outputs: [nagios]", "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("outputs.0".to_string()));
+
+        let err = super::parse("outputs: [prometheus]\nprometheus:\n  listen: 9128", "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("prometheus.listen".to_string()));
+    }
+
+    #[test]
+    fn test_parse_no_outputs() {
+        assert!(super::parse("", "test.yaml", &[], LogLevel::Info).is_err());
+        assert!(super::parse("outputs: []", "test.yaml", &[], LogLevel::Info).is_err());
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let file = r#"
+outputs:
+  - stdout
+influx:
+  host: 127.0.0.1
+"#;
+        let overrides = vec![
+            super::Override {
+                setting: super::setting("outputs").unwrap(),
+                value: "stdout, influx".to_string(),
+                source: "--outputs".to_string(),
+            },
+            super::Override {
+                setting: super::setting("influx.port").unwrap(),
+                value: "9086".to_string(),
+                source: "--influx-port".to_string(),
+            },
+        ];
+        let args = super::parse(file, "test.yaml", &overrides, LogLevel::Info).unwrap();
+        assert_eq!(args.outputs, vec!["stdout", "influx"]);
+        let influx = args.influx.unwrap();
+        assert_eq!(influx.port, 9086);
+        assert_eq!(influx.host, "127.0.0.1");
+
+        let bad_port = vec![
+            super::Override {
+                setting: super::setting("influx.port").unwrap(),
+                value: "http".to_string(),
+                source: "environment variable ADMIN_CEPH_INFLUX_PORT".to_string(),
+            },
+        ];
+        let err = super::parse(file, "test.yaml", &bad_port, LogLevel::Info).unwrap_err();
+        assert_eq!(err.file, "environment variable ADMIN_CEPH_INFLUX_PORT");
+        assert_eq!(err.line, None);
+
+        // Nothing in the file at all
+        let args = super::parse("", "test.yaml", &overrides, LogLevel::Info).unwrap();
+        assert_eq!(args.outputs, vec!["stdout", "influx"]);
+    }
+
+    #[test]
+    fn test_parse_stdout() {
+        let args = super::parse("outputs: [stdout]", "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.stdout.format, super::Format::Json);
+        assert_eq!(args.stdout.stream, super::Stream::Stdout);
+
+        let file = r#"
+outputs:
+  - stdout
+stdout:
+  format: line_protocol
+  stream: stderr
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.stdout.format, super::Format::LineProtocol);
+        assert_eq!(args.stdout.stream, super::Stream::Stderr);
+
+        let file = r#"
+outputs:
+  - stdout
+stdout:
+  format: xml
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("stdout.format".to_string()));
+        assert_eq!(err.line, Some(5));
+    }
+
+    #[test]
+    fn test_parse_graphite() {
+        let file = r#"
+outputs:
+  - graphite
+graphite:
+  protocol: pickle
+  templates:
+    osd_daemon: dc1.{hostname}.osd.{osd_num}
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.graphite.port, 2004);
+        assert_eq!(args.graphite.template("osd_daemon"), "dc1.{hostname}.osd.{osd_num}");
+        assert_eq!(args.graphite.template("mon_daemon"), "ceph.{cluster}.{hostname}.mon");
+        assert_eq!(args.graphite.template("something_new"), "ceph.{hostname}.{measurement}");
+
+        let file = r#"
+outputs:
+  - graphite
+graphite:
+  templates:
+    osd_daemon: ceph.{hostname.osd
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("graphite.templates.osd_daemon".to_string()));
+        assert_eq!(err.line, Some(6));
+    }
+
+    #[test]
+    fn test_parse_collectors() {
+        let file = r#"
+outputs:
+  - influx
+collectors:
+  osd:
+    interval_ms: 10000
+    perf_dump: full
+    rates: true
+  packets:
+    enabled: false
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        assert!(args.collectors.monitor.enabled);
+        assert_eq!(args.collectors.monitor.interval_ms, 5000);
+        assert_eq!(args.collectors.monitor.perf_dump, super::PerfDump::Curated);
+        assert_eq!(args.collectors.osd.interval_ms, 10000);
+        assert_eq!(args.collectors.osd.rediscover_every, 10);
+        assert_eq!(args.collectors.osd.perf_dump, super::PerfDump::Full);
+        assert!(args.collectors.osd.rates);
+        assert!(!args.collectors.monitor.rates);
+        assert!(!args.collectors.packets.enabled);
+
+        let file = r#"
+outputs:
+  - influx
+collectors:
+  packets:
+    interval_ms: 10000
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("collectors.packets.interval_ms".to_string()));
+
+        let file = r#"
+outputs:
+  - influx
+collectors:
+  monitor:
+    rates: true
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("collectors.monitor.rates".to_string()));
+        let args = super::parse(&format!("{}    perf_dump: full\n", file), "test.yaml", &[], LogLevel::Info).unwrap();
+        assert!(args.collectors.monitor.rates);
+    }
+
+    #[test]
+    fn test_parse_ceph() {
+        let file = r#"
+outputs:
+  - influx
+ceph:
+  cluster: backup
+  admin_socket: /run/ceph/$cluster/$type-$id.sock
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        let ceph = &args.clusters[0];
+        assert_eq!(ceph.expand(&ceph.admin_socket, "osd", "3"), "/run/ceph/backup/osd-3.sock");
+        assert_eq!(ceph.expand(&ceph.mon_data, "mon", "a"), "/var/lib/ceph/mon/backup-a");
+
+        let file = r#"
+outputs:
+  - influx
+ceph:
+  admin_socket: /var/run/ceph/$name/admin.asok
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("ceph.admin_socket".to_string()));
+    }
+
+    #[test]
+    fn test_parse_clusters() {
+        let args = super::parse("outputs: [influx]", "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.clusters.len(), 1);
+        assert_eq!(args.clusters[0].cluster, "ceph");
+
+        let file = r#"
+outputs:
+  - influx
+ceph:
+  - cluster: ceph
+  - cluster: backup
+    admin_socket: /var/run/ceph/backup/$name.asok
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        let names: Vec<&str> = args.clusters.iter().map(|c| &c.cluster[..]).collect();
+        assert_eq!(names, vec!["ceph", "backup"]);
+        assert_eq!(args.clusters[1].admin_socket, "/var/run/ceph/backup/$name.asok");
+
+        let file = r#"
+outputs:
+  - influx
+ceph:
+  - cluster: backup
+  - cluster: backup
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("ceph.1.cluster".to_string()));
+        assert_eq!(err.line, Some(6));
+
+        // An override can't say which of the clusters it's for
+        let file = r#"
+outputs:
+  - influx
+ceph:
+  - cluster: ceph
+  - cluster: backup
+"#;
+        let overrides = vec![
+            super::Override {
+                setting: super::setting("ceph.admin_socket").unwrap(),
+                value: "/run/ceph/$cluster-$name.asok".to_string(),
+                source: "--ceph-admin-socket".to_string(),
+            },
+        ];
+        let err = super::parse(file, "test.yaml", &overrides, LogLevel::Info).unwrap_err();
+        assert_eq!(err.file, "--ceph-admin-socket");
+        assert_eq!(err.key, Some("ceph.admin_socket".to_string()));
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let file = r#"
+outputs:
+  - influx
+hostname: osd-host-1
+tags:
+  datacenter: dc1
+  rack: 12
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.hostname, "osd-host-1");
+        assert_eq!(args.tags.get("datacenter"), Some(&"dc1".to_string()));
+        assert_eq!(args.tags.get("rack"), Some(&"12".to_string()));
+
+        let file = r#"
+outputs:
+  - influx
+tags:
+  hostname: other
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("tags.hostname".to_string()));
+
+        let overrides = vec![
+            super::Override {
+                setting: super::setting("tags").unwrap(),
+                value: "environment=prod,rack=4".to_string(),
+                source: "--tags".to_string(),
+            },
+        ];
+        let args = super::parse("outputs: [influx]", "test.yaml", &overrides, LogLevel::Info).unwrap();
+        assert_eq!(args.tags.get("environment"), Some(&"prod".to_string()));
+        assert_eq!(args.tags.get("rack"), Some(&"4".to_string()));
+    }
+
+    #[test]
+    fn test_parse_secrets() {
+        use std::env;
+        use std::fs::File;
+        use std::io::Write;
+
+        let path = env::temp_dir().join("admin_ceph_test_password");
+        File::create(&path).unwrap().write_all(b"from-a-file\n").unwrap();
+        let file = format!(r#"
+outputs:
+  - influx
+influx:
+  password_file: {}
+"#, path.display());
+        let args = super::parse(&file, "test.yaml", &[], LogLevel::Info).unwrap();
+        let influx = args.influx.unwrap();
+        assert_eq!(influx.password.as_ref().map(|p| p.expose()), Some("from-a-file"));
+        assert!(!format!("{:?}", influx).contains("from-a-file"));
+
+        env::set_var("ADMIN_CEPH_TEST_PASSWORD", "from-the-env");
+        let file = r#"
+outputs:
+  - influx
+influx:
+  password_env: ADMIN_CEPH_TEST_PASSWORD
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.influx.unwrap().password.unwrap().expose(), "from-the-env");
+
+        let file = r#"
+outputs:
+  - influx
+influx:
+  password_file: /nonexistent/admin_ceph/password
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("influx.password_file".to_string()));
+
+        let file = r#"
+outputs:
+  - influx
+influx:
+  password: hunter2
+  password_env: ADMIN_CEPH_TEST_PASSWORD
+"#;
+        assert!(super::parse(file, "test.yaml", &[], LogLevel::Info).is_err());
+
+        // An override replaces the file's secret however it was given
+        let file = r#"
+outputs:
+  - influx
+influx:
+  password_file: /nonexistent/admin_ceph/password
+"#;
+        let overrides = vec![
+            super::Override {
+                setting: super::setting("influx.password_env").unwrap(),
+                value: "ADMIN_CEPH_TEST_PASSWORD".to_string(),
+                source: "--influx-password-env".to_string(),
+            },
+        ];
+        let args = super::parse(file, "test.yaml", &overrides, LogLevel::Info).unwrap();
+        assert_eq!(args.influx.unwrap().password.unwrap().expose(), "from-the-env");
+
+        let file = r#"
+outputs:
+  - influx
+influx:
+  api: v2
+  org: storage
+  token: from-the-file
+"#;
+        let overrides = vec![
+            super::Override {
+                setting: super::setting("influx.token_file").unwrap(),
+                value: path.display().to_string(),
+                source: "environment variable ADMIN_CEPH_INFLUX_TOKEN_FILE".to_string(),
+            },
+        ];
+        let args = super::parse(file, "test.yaml", &overrides, LogLevel::Info).unwrap();
+        assert_eq!(args.influx.unwrap().token.unwrap().expose(), "from-a-file");
+
+        // No credentials at all means no authentication
+        let args = super::parse("outputs: [influx]", "test.yaml", &[], LogLevel::Info).unwrap();
+        assert!(args.influx.unwrap().password.is_none());
+    }
+
+    #[test]
+    fn test_parse_influx_v2() {
+        let file = r#"
+outputs:
+  - influx
+influx:
+  api: v2
+  bucket: ceph/autogen
+  org: storage
+  token: s3cret
+  precision: ms
+  https: true
+  ca_file: /etc/ssl/influx-ca.pem
+"#;
+        let influx = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap().influx.unwrap();
+        assert_eq!(influx.api, super::InfluxApi::V2);
+        assert_eq!(influx.bucket, "ceph/autogen");
+        assert_eq!(influx.org, Some("storage".to_string()));
+        assert_eq!(influx.token.unwrap().expose(), "s3cret");
+        assert_eq!(influx.precision, ::metric::Precision::Milliseconds);
+        assert_eq!(influx.ca_file, Some("/etc/ssl/influx-ca.pem".to_string()));
+
+        let err = super::parse("outputs: [influx]\ninflux: {api: v2, token: s3cret}", "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("influx.org".to_string()));
+        let err = super::parse("outputs: [influx]\ninflux: {api: v2, org: storage, token: s3cret, retention_policy: week}",
+                               "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("influx.retention_policy".to_string()));
+        let err = super::parse("outputs: [influx]\ninflux: {ca_file: /etc/ssl/influx-ca.pem}", "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("influx.ca_file".to_string()));
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let file = r#"
+outputs: [influx, file]
+routes:
+  influx:
+    measurements:
+      exclude: ["^osd_operation$"]
+    fields:
+      include: ["^op_", "^stat_"]
+    rename_fields:
+      op_latency: op_latency_seconds
+    drop_tags: [drive_name]
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        let route = &args.routes["influx"];
+        assert_eq!(route.measurements.exclude, vec!["^osd_operation$"]);
+        assert!(route.measurements.include.is_empty());
+        assert_eq!(route.fields.include, vec!["^op_", "^stat_"]);
+        assert_eq!(route.rename_fields["op_latency"], "op_latency_seconds");
+        assert_eq!(route.drop_tags, vec!["drive_name"]);
+        assert!(!args.routes.contains_key("file"));
+
+        let err = super::parse("outputs: [influx]\nroutes: {influx: {fields: {exclude: [\"(\"]}}}",
+                               "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("routes.influx.fields.exclude".to_string()));
+        let err = super::parse("outputs: [influx]\nroutes: {nagios: {}}", "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("routes.nagios".to_string()));
+    }
+
+    #[test]
+    fn test_parse_aggregator() {
+        let args = super::parse("mode: aggregator\noutputs: [stdout]", "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.aggregator.listen, "127.0.0.1:9127");
+        assert!(args.aggregator.token.is_none());
+
+        // Listening beyond the loopback takes a token
+        let file = r#"
+mode: aggregator
+outputs: [stdout]
+aggregator:
+  listen: 0.0.0.0:9127
+"#;
+        let err = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap_err();
+        assert_eq!(err.key, Some("aggregator.listen".to_string()));
+
+        let file = r#"
+mode: aggregator
+outputs: [stdout]
+aggregator:
+  listen: 0.0.0.0:9127
+  token: s3cret
+  max_agents: 10
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.aggregator.token.as_ref().map(|t| t.expose()), Some("s3cret"));
+        assert_eq!(args.aggregator.max_agents, 10);
+        assert!(!args.to_yaml_string().contains("s3cret"));
+
+        let file = r#"
+outputs: [forward]
+forward:
+  host: aggregator.example.com
+  token: s3cret
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(args.forward.token.unwrap().expose(), "s3cret");
+    }
+
+    #[test]
+    fn test_print_config() {
+        let file = r#"
+outputs:
+  - influx
+influx:
+  host: influx.example.com
+  password: hunter2
+"#;
+        let args = super::parse(file, "test.yaml", &[], LogLevel::Info).unwrap();
+        let printed = args.to_yaml_string();
+        assert!(!printed.contains("hunter2"));
+        assert!(printed.contains("<redacted>"));
+
+        // The printed config is itself a valid config file
+        let reparsed = super::parse(&printed, "printed.yaml", &[], LogLevel::Info).unwrap();
+        assert_eq!(reparsed.influx.unwrap().host, "influx.example.com");
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct Args {
+    pub influx: Option<Influx>,
+    pub stdout: Stdout,
+    pub prometheus: Prometheus,
+    pub graphite: Graphite,
+    pub statsd: Statsd,
+    pub opentsdb: OpenTsdb,
+    pub file: Archive,
+    pub influx_udp: InfluxUdp,
+    pub forward: Forward,
+    pub otlp: Otlp,
+    pub aggregator: Aggregator,
+    // Fixed at startup, a reload can't switch it
+    pub mode: Mode,
+    pub outputs: Vec<String>,
+    // Keyed by output name, outputs without one get everything
+    pub routes: BTreeMap<String, Route>,
+    pub config_path: String,
+    pub log_level: LogLevel,
+    pub hostname: String,
+    // Whether the hostname was looked up as a fully qualified name
+    pub fqdn: bool,
+    // Added to every measurement
+    pub tags: BTreeMap<String, String>,
+    pub collectors: Collectors,
+    // Every cluster with daemons on this host
+    pub clusters: Vec<Ceph>,
+}
+
+/// Where to find the daemons of a Ceph cluster.  The paths are templates
+/// in the same form as ceph.conf: `$cluster`, `$type`, `$id` and `$name`
+/// (`$type.$id`) are replaced for each daemon.
+#[derive(Clone,Debug)]
+pub struct Ceph {
+    pub cluster: String,
+    pub admin_socket: String,
+    pub mon_data: String,
+    pub osd_data: String,
+}
+
+impl Default for Ceph {
+    fn default() -> Ceph {
+        Ceph {
+            cluster: "ceph".to_string(),
+            admin_socket: "/var/run/ceph/$cluster-$name.asok".to_string(),
+            mon_data: "/var/lib/ceph/mon/$cluster-$id".to_string(),
+            osd_data: "/var/lib/ceph/osd/$cluster-$id".to_string(),
+        }
+    }
+}
+
+impl Ceph {
+    /// Fill in a path template for one daemon, ie: `expand(&ceph.admin_socket, "osd", "3")`
+    pub fn expand(&self, template: &str, daemon_type: &str, id: &str) -> String {
+        template.replace("$cluster", &self.cluster)
+                .replace("$name", &format!("{}.{}", daemon_type, id))
+                .replace("$type", daemon_type)
+                .replace("$id", id)
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct Collectors {
+    pub monitor: Collector,
+    pub osd: Collector,
+    // Only `enabled` applies, packets are logged as they are captured
+    pub packets: Collector,
+}
+
+/// How often a collector polls, and after how many polls it looks for
+/// daemons that have appeared or gone away
+#[derive(Clone,Debug)]
+pub struct Collector {
+    pub enabled: bool,
+    pub interval_ms: u64,
+    pub rediscover_every: u64,
+    pub perf_dump: PerfDump,
+    // Also send what the counters did since the last poll
+    pub rates: bool,
+}
+
+impl Default for Collector {
+    fn default() -> Collector {
+        Collector {
+            enabled: true,
+            interval_ms: 5000,
+            rediscover_every: 10,
+            perf_dump: PerfDump::Curated,
+            rates: false,
+        }
+    }
+}
+
+/// Which of a daemon's perf counters are sent
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum PerfDump {
+    // The hand picked fields, named as they always have been
+    Curated,
+    // Every counter in the perf dump, named for its section
+    Full,
+}
+
+impl PerfDump {
+    fn name(&self) -> &'static str {
+        match *self {
+            PerfDump::Curated => "curated",
+            PerfDump::Full => "full",
+        }
+    }
+}
+
+/// How the `stdout` output writes measurements
+#[derive(Clone,Debug)]
+pub struct Stdout {
+    pub format: Format,
+    pub stream: Stream,
+}
+
+impl Default for Stdout {
+    fn default() -> Stdout {
+        Stdout {
+            format: Format::Json,
+            stream: Stream::Stdout,
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Format {
+    // One JSON object per line
+    Json,
+    // Influx line protocol
+    LineProtocol,
+}
+
+impl Format {
+    fn name(&self) -> &'static str {
+        match *self {
+            Format::Json => "json",
+            Format::LineProtocol => "line_protocol",
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone,Debug)]
+pub struct Prometheus {
+    // Address the /metrics endpoint listens on
+    pub listen: String,
+}
+
+impl Default for Prometheus {
+    fn default() -> Prometheus {
+        Prometheus {
+            listen: "0.0.0.0:9128".to_string(),
+        }
+    }
+}
+
+/// Where and how to send to Carbon.  Each measurement's fields are sent
+/// below the path its template expands to, `{tag}` being replaced with the
+/// measurement's tag and `{measurement}` with its name.
+#[derive(Clone,Debug)]
+pub struct Graphite {
+    pub host: String,
+    pub port: u16,
+    pub protocol: GraphiteProtocol,
+    // Keyed by measurement name
+    pub templates: BTreeMap<String, String>,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum GraphiteProtocol {
+    Plaintext,
+    Pickle,
+}
+
+impl GraphiteProtocol {
+    fn name(&self) -> &'static str {
+        match *self {
+            GraphiteProtocol::Plaintext => "plaintext",
+            GraphiteProtocol::Pickle => "pickle",
+        }
+    }
+
+    // Carbon's usual ports
+    fn default_port(&self) -> u16 {
+        match *self {
+            GraphiteProtocol::Plaintext => 2003,
+            GraphiteProtocol::Pickle => 2004,
+        }
+    }
+}
+
+impl Graphite {
+    fn default_templates() -> BTreeMap<String, String> {
+        let mut templates = BTreeMap::new();
+        templates.insert("mon_daemon".to_string(), "ceph.{cluster}.{hostname}.mon".to_string());
+        templates.insert("osd_daemon".to_string(), "ceph.{cluster}.{hostname}.osd.{osd_num}".to_string());
+        templates.insert("osd_operation".to_string(), "ceph.{hostname}.packets.{type}".to_string());
+        templates
+    }
+
+    pub fn template(&self, measurement: &str) -> &str {
+        self.templates.get(measurement).map_or(DEFAULT_GRAPHITE_TEMPLATE, |t| &t[..])
+    }
+}
+
+/// Plain StatsD names come from the Graphite templates, since that's where
+/// StatsD usually sends them.  With `dogstatsd` they're
+/// `<prefix>.<measurement>.<field>` and the tags are sent as tags.
+#[derive(Clone,Debug)]
+pub struct Statsd {
+    pub host: String,
+    pub port: u16,
+    pub dogstatsd: bool,
+    pub prefix: String,
+}
+
+impl Default for Statsd {
+    fn default() -> Statsd {
+        Statsd {
+            host: "127.0.0.1".to_string(),
+            port: 8125,
+            dogstatsd: false,
+            prefix: "ceph".to_string(),
+        }
+    }
+}
+
+/// Metrics are named `<prefix>.<measurement>.<field>` and keep their tags
+#[derive(Clone,Debug)]
+pub struct OpenTsdb {
+    pub host: String,
+    pub port: u16,
+    pub protocol: OpenTsdbProtocol,
+    pub prefix: String,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum OpenTsdbProtocol {
+    // JSON datapoints POSTed to /api/put
+    Http,
+    // `put` lines
+    Telnet,
+}
+
+impl Default for OpenTsdb {
+    fn default() -> OpenTsdb {
+        OpenTsdb {
+            host: "127.0.0.1".to_string(),
+            port: 4242,
+            protocol: OpenTsdbProtocol::Http,
+            prefix: "ceph".to_string(),
+        }
+    }
+}
+
+/// A local archive of every measurement as JSON Lines.  The file being
+/// written is closed and a new one started once it's `max_size_mb` big or
+/// `rotate_secs` old.
+#[derive(Clone,Debug)]
+pub struct Archive {
+    // Directory the files are kept in
+    pub path: String,
+    pub max_size_mb: u64,
+    pub rotate_secs: u64,
+    // Compress closed files
+    pub gzip: bool,
+    // How many closed files to keep
+    pub keep: u64,
+}
+
+impl Default for Archive {
+    fn default() -> Archive {
+        Archive {
+            path: "/var/lib/admin_ceph/archive".to_string(),
+            max_size_mb: 64,
+            rotate_secs: 3600,
+            gzip: true,
+            keep: 168,
+        }
+    }
+}
+
+/// Which measurements and fields an output is sent, and how they're changed
+/// on the way.  Patterns are regular expressions matched against names;
+/// with no `include` every name is included, and `exclude` wins over
+/// `include`.
+#[derive(Clone,Debug,Default)]
+pub struct Route {
+    pub measurements: Filter,
+    pub fields: Filter,
+    // Old field name to new, after filtering
+    pub rename_fields: BTreeMap<String, String>,
+    pub drop_tags: Vec<String>,
+}
+
+#[derive(Clone,Debug,Default)]
+pub struct Filter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Filter {
+    fn to_yaml(&self) -> Yaml {
+        let list = |patterns: &Vec<String>| Yaml::Array(patterns.iter().map(|p| Yaml::String(p.clone())).collect());
+        hash(vec![("include", list(&self.include)), ("exclude", list(&self.exclude))])
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Mode {
+    // Collect from the daemons on this host
+    Agent,
+    // Collect from agents that forward to it
+    Aggregator,
+}
+
+/// The aggregator an agent's `forward` output sends to, and the token it
+/// expects, if any
+#[derive(Clone,Debug)]
+pub struct Forward {
+    pub host: String,
+    pub port: u16,
+    pub token: Option<Secret>,
+}
+
+impl Default for Forward {
+    fn default() -> Forward {
+        Forward {
+            host: "127.0.0.1".to_string(),
+            port: 9127,
+            token: None,
+        }
+    }
+}
+
+/// An OpenTelemetry collector's OTLP/HTTP receiver, sent protobuf
+#[derive(Clone,Debug)]
+pub struct Otlp {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Default for Otlp {
+    fn default() -> Otlp {
+        Otlp {
+            host: "127.0.0.1".to_string(),
+            port: 4318,
+            path: "/v1/metrics".to_string(),
+        }
+    }
+}
+
+/// Where an aggregator listens for agents.  Every monitor reports the
+/// same cluster wide figures, so only one `mon_daemon` measurement per
+/// cluster is kept every `dedupe_secs`.  Agents have to present `token`
+/// when one is set, which it has to be to listen beyond the loopback.
+#[derive(Clone,Debug)]
+pub struct Aggregator {
+    pub listen: String,
+    pub dedupe_secs: u64,
+    pub token: Option<Secret>,
+    // Connections beyond this are turned away
+    pub max_agents: u64,
+}
+
+impl Default for Aggregator {
+    fn default() -> Aggregator {
+        Aggregator {
+            listen: "127.0.0.1:9127".to_string(),
+            dedupe_secs: 5,
+            token: None,
+            max_agents: 256,
+        }
+    }
+}
+
+/// Line protocol over UDP, to the listener InfluxDB can run alongside its
+/// HTTP API.  `precision` has to match the listener's.
+#[derive(Clone,Debug)]
+pub struct InfluxUdp {
+    pub host: String,
+    pub port: u16,
+    pub precision: Precision,
+}
+
+impl Default for InfluxUdp {
+    fn default() -> InfluxUdp {
+        InfluxUdp {
+            host: "127.0.0.1".to_string(),
+            port: 8089,
+            precision: Precision::Nanoseconds,
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum InfluxApi {
+    // /write, into a database and retention policy
+    V1,
+    // /api/v2/write, into a bucket of an org
+    V2,
+}
+
+#[derive(Clone,Debug)]
+pub struct Influx {
+    pub user: String,
+    pub password: Option<Secret>,
+    pub host: String,
+    pub port: u16,
+    pub api: InfluxApi,
+    pub database: String,
+    pub retention_policy: Option<String>,
+    pub bucket: String,
+    pub org: Option<String>,
+    pub token: Option<Secret>,
+    pub precision: Precision,
+    // Without a `ca_file` the server is checked against the system's CAs.
+    // `cert_file` and `key_file` are for servers that want a client
+    // certificate.
+    pub https: bool,
+    pub ca_file: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    // Points are sent once this many are waiting, or the oldest has waited
+    // `flush_ms`
+    pub batch_size: u64,
+    pub flush_ms: u64,
+    // Where batches wait while InfluxDB is unavailable, and how big that
+    // may grow before the oldest are dropped
+    pub spool_path: String,
+    pub spool_max_mb: u64,
+}
+
+impl Default for Influx {
+    fn default() -> Influx {
+        Influx {
+            user: String::new(),
+            password: None,
+            host: "127.0.0.1".to_string(),
+            port: 8086,
+            api: InfluxApi::V1,
+            database: "ceph".to_string(),
+            retention_policy: None,
+            bucket: "ceph".to_string(),
+            org: None,
+            token: None,
+            precision: Precision::Seconds,
+            https: false,
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            batch_size: 5000,
+            flush_ms: 1000,
+            spool_path: "/var/lib/admin_ceph/spool".to_string(),
+            spool_max_mb: 256,
+        }
+    }
+}
+
+impl Args {
+    /// The effective configuration as it would be written in the config
+    /// file, with secrets redacted
+    pub fn to_yaml(&self) -> Yaml {
+        let mut root = vec![
+            ("mode", Yaml::String(match self.mode {
+                Mode::Agent => "agent",
+                Mode::Aggregator => "aggregator",
+            }.to_string())),
+            ("outputs", Yaml::Array(self.outputs.iter().map(|o| Yaml::String(o.clone())).collect())),
+        ];
+        root.push(("stdout", hash(vec![
+            ("format", Yaml::String(self.stdout.format.name().to_string())),
+            ("stream", Yaml::String(match self.stdout.stream {
+                Stream::Stdout => "stdout",
+                Stream::Stderr => "stderr",
+            }.to_string())),
+        ])));
+        root.push(("prometheus", hash(vec![
+            ("listen", Yaml::String(self.prometheus.listen.clone())),
+        ])));
+        let mut templates = BTreeMap::new();
+        for (measurement, template) in self.graphite.templates.iter() {
+            templates.insert(Yaml::String(measurement.clone()), Yaml::String(template.clone()));
+        }
+        root.push(("graphite", hash(vec![
+            ("host", Yaml::String(self.graphite.host.clone())),
+            ("port", Yaml::Integer(self.graphite.port as i64)),
+            ("protocol", Yaml::String(self.graphite.protocol.name().to_string())),
+            ("templates", Yaml::Hash(templates)),
+        ])));
+        root.push(("statsd", hash(vec![
+            ("host", Yaml::String(self.statsd.host.clone())),
+            ("port", Yaml::Integer(self.statsd.port as i64)),
+            ("dogstatsd", Yaml::Boolean(self.statsd.dogstatsd)),
+            ("prefix", Yaml::String(self.statsd.prefix.clone())),
+        ])));
+        root.push(("opentsdb", hash(vec![
+            ("host", Yaml::String(self.opentsdb.host.clone())),
+            ("port", Yaml::Integer(self.opentsdb.port as i64)),
+            ("protocol", Yaml::String(match self.opentsdb.protocol {
+                OpenTsdbProtocol::Http => "http",
+                OpenTsdbProtocol::Telnet => "telnet",
+            }.to_string())),
+            ("prefix", Yaml::String(self.opentsdb.prefix.clone())),
+        ])));
+        root.push(("file", hash(vec![
+            ("path", Yaml::String(self.file.path.clone())),
+            ("max_size_mb", Yaml::Integer(self.file.max_size_mb as i64)),
+            ("rotate_secs", Yaml::Integer(self.file.rotate_secs as i64)),
+            ("gzip", Yaml::Boolean(self.file.gzip)),
+            ("keep", Yaml::Integer(self.file.keep as i64)),
+        ])));
+        let mut forward = vec![
+            ("host", Yaml::String(self.forward.host.clone())),
+            ("port", Yaml::Integer(self.forward.port as i64)),
+        ];
+        if self.forward.token.is_some() {
+            forward.push(("token", Yaml::String(REDACTED.to_string())));
+        }
+        root.push(("forward", hash(forward)));
+        root.push(("otlp", hash(vec![
+            ("host", Yaml::String(self.otlp.host.clone())),
+            ("port", Yaml::Integer(self.otlp.port as i64)),
+            ("path", Yaml::String(self.otlp.path.clone())),
+        ])));
+        let mut aggregator = vec![
+            ("listen", Yaml::String(self.aggregator.listen.clone())),
+            ("dedupe_secs", Yaml::Integer(self.aggregator.dedupe_secs as i64)),
+            ("max_agents", Yaml::Integer(self.aggregator.max_agents as i64)),
+        ];
+        if self.aggregator.token.is_some() {
+            aggregator.push(("token", Yaml::String(REDACTED.to_string())));
+        }
+        root.push(("aggregator", hash(aggregator)));
+        root.push(("influx_udp", hash(vec![
+            ("host", Yaml::String(self.influx_udp.host.clone())),
+            ("port", Yaml::Integer(self.influx_udp.port as i64)),
+            ("precision", Yaml::String(self.influx_udp.precision.name().to_string())),
+        ])));
+        if let Some(ref influx) = self.influx {
+            let mut entries = vec![
+                ("host", Yaml::String(influx.host.clone())),
+                ("port", Yaml::Integer(influx.port as i64)),
+                ("https", Yaml::Boolean(influx.https)),
+                ("precision", Yaml::String(influx.precision.name().to_string())),
+                ("batch_size", Yaml::Integer(influx.batch_size as i64)),
+                ("flush_ms", Yaml::Integer(influx.flush_ms as i64)),
+                ("spool_path", Yaml::String(influx.spool_path.clone())),
+                ("spool_max_mb", Yaml::Integer(influx.spool_max_mb as i64)),
+            ];
+            match influx.api {
+                InfluxApi::V1 => {
+                    entries.push(("api", Yaml::String("v1".to_string())));
+                    entries.push(("database", Yaml::String(influx.database.clone())));
+                    if let Some(ref rp) = influx.retention_policy {
+                        entries.push(("retention_policy", Yaml::String(rp.clone())));
+                    }
+                    entries.push(("user", Yaml::String(influx.user.clone())));
+                    if influx.password.is_some() {
+                        entries.push(("password", Yaml::String(REDACTED.to_string())));
+                    }
+                },
+                InfluxApi::V2 => {
+                    entries.push(("api", Yaml::String("v2".to_string())));
+                    entries.push(("bucket", Yaml::String(influx.bucket.clone())));
+                    if let Some(ref org) = influx.org {
+                        entries.push(("org", Yaml::String(org.clone())));
+                    }
+                    if influx.token.is_some() {
+                        entries.push(("token", Yaml::String(REDACTED.to_string())));
+                    }
+                },
+            }
+            for &(key, ref file) in [("ca_file", &influx.ca_file), ("cert_file", &influx.cert_file),
+                                     ("key_file", &influx.key_file)].iter() {
+                if let Some(ref file) = **file {
+                    entries.push((key, Yaml::String(file.clone())));
+                }
+            }
+            root.push(("influx", hash(entries)));
+        }
+        root.push(("hostname", Yaml::String(self.hostname.clone())));
+        root.push(("fqdn", Yaml::Boolean(self.fqdn)));
+        if !self.routes.is_empty() {
+            let mut routes = BTreeMap::new();
+            for (output, route) in self.routes.iter() {
+                let mut rename_fields = BTreeMap::new();
+                for (from, to) in route.rename_fields.iter() {
+                    rename_fields.insert(Yaml::String(from.clone()), Yaml::String(to.clone()));
+                }
+                routes.insert(Yaml::String(output.clone()), hash(vec![
+                    ("measurements", route.measurements.to_yaml()),
+                    ("fields", route.fields.to_yaml()),
+                    ("rename_fields", Yaml::Hash(rename_fields)),
+                    ("drop_tags", Yaml::Array(route.drop_tags.iter().map(|t| Yaml::String(t.clone())).collect())),
+                ]));
+            }
+            root.push(("routes", Yaml::Hash(routes)));
+        }
+        if !self.tags.is_empty() {
+            let mut tags = BTreeMap::new();
+            for (key, value) in self.tags.iter() {
+                tags.insert(Yaml::String(key.clone()), Yaml::String(value.clone()));
+            }
+            root.push(("tags", Yaml::Hash(tags)));
+        }
+        let mut clusters: Vec<Yaml> = self.clusters.iter().map(|c| c.to_yaml()).collect();
+        if clusters.len() == 1 {
+            root.push(("ceph", clusters.remove(0)));
+        } else {
+            root.push(("ceph", Yaml::Array(clusters)));
+        }
+        root.push(("collectors", hash(vec![
+            ("monitor", self.collectors.monitor.to_yaml(true)),
+            ("osd", self.collectors.osd.to_yaml(true)),
+            ("packets", self.collectors.packets.to_yaml(false)),
+        ])));
+        hash(root)
+    }
+
+    pub fn to_yaml_string(&self) -> String {
+        let mut out = String::new();
+        {
+            let mut emitter = YamlEmitter::new(&mut out);
+            emitter.dump(&self.to_yaml()).unwrap();
+        }
+        out.push('\n');
+        out
+    }
+}
+
+impl Ceph {
+    fn to_yaml(&self) -> Yaml {
+        hash(vec![
+            ("cluster", Yaml::String(self.cluster.clone())),
+            ("admin_socket", Yaml::String(self.admin_socket.clone())),
+            ("mon_data", Yaml::String(self.mon_data.clone())),
+            ("osd_data", Yaml::String(self.osd_data.clone())),
+        ])
+    }
+}
+
+impl Collector {
+    fn to_yaml(&self, polled: bool) -> Yaml {
+        let mut entries = vec![("enabled", Yaml::Boolean(self.enabled))];
+        if polled {
+            entries.push(("interval_ms", Yaml::Integer(self.interval_ms as i64)));
+            entries.push(("rediscover_every", Yaml::Integer(self.rediscover_every as i64)));
+            entries.push(("perf_dump", Yaml::String(self.perf_dump.name().to_string())));
+            entries.push(("rates", Yaml::Boolean(self.rates)));
+        }
+        hash(entries)
+    }
+}
+
+fn hash(entries: Vec<(&str, Yaml)>) -> Yaml {
+    let mut hash = BTreeMap::new();
+    for (key, value) in entries {
+        hash.insert(Yaml::String(key.to_string()), value);
+    }
+    Yaml::Hash(hash)
+}
+
+/// A credential.  Its Debug output is redacted so it can't leak into logs.
+#[derive(Clone,PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+/// A config key that can also be set from the command line as `--<flag>`
+/// or from the environment as `ADMIN_CEPH_<KEY>`.  New keys belong in
+/// `SETTINGS` so they pick both up.
+pub struct Setting {
+    pub key: &'static str,
+    pub flag: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Kind {
+    Text,
+    Integer,
+    Boolean,
+    // Comma separated on the command line and in the environment
+    List,
+    // Comma separated key=value pairs
+    Map,
+}
+
+pub static SETTINGS: &'static [Setting] = &[
+    Setting { key: "mode", flag: "mode", kind: Kind::Text, help: "agent to collect from this host, aggregator to collect from agents" },
+    Setting { key: "outputs", flag: "outputs", kind: Kind::List, help: "Comma separated list of outputs to log to" },
+    Setting { key: "stdout.format", flag: "stdout-format", kind: Kind::Text, help: "Format of the stdout output: json or line_protocol" },
+    Setting { key: "stdout.stream", flag: "stdout-stream", kind: Kind::Text, help: "Stream the stdout output writes to: stdout or stderr" },
+    Setting { key: "prometheus.listen", flag: "prometheus-listen", kind: Kind::Text, help: "Address to serve Prometheus /metrics on" },
+    Setting { key: "graphite.host", flag: "graphite-host", kind: Kind::Text, help: "Carbon host" },
+    Setting { key: "graphite.port", flag: "graphite-port", kind: Kind::Integer, help: "Carbon port, 2003 for plaintext and 2004 for pickle by default" },
+    Setting { key: "graphite.protocol", flag: "graphite-protocol", kind: Kind::Text, help: "Carbon protocol: plaintext or pickle" },
+    Setting { key: "statsd.host", flag: "statsd-host", kind: Kind::Text, help: "StatsD host" },
+    Setting { key: "statsd.port", flag: "statsd-port", kind: Kind::Integer, help: "StatsD port" },
+    Setting { key: "statsd.dogstatsd", flag: "statsd-dogstatsd", kind: Kind::Boolean, help: "Send tags with the DogStatsD extension" },
+    Setting { key: "statsd.prefix", flag: "statsd-prefix", kind: Kind::Text, help: "Prefix of DogStatsD metric names" },
+    Setting { key: "opentsdb.host", flag: "opentsdb-host", kind: Kind::Text, help: "OpenTSDB host" },
+    Setting { key: "opentsdb.port", flag: "opentsdb-port", kind: Kind::Integer, help: "OpenTSDB port" },
+    Setting { key: "opentsdb.protocol", flag: "opentsdb-protocol", kind: Kind::Text, help: "OpenTSDB protocol: http or telnet" },
+    Setting { key: "opentsdb.prefix", flag: "opentsdb-prefix", kind: Kind::Text, help: "Prefix of OpenTSDB metric names" },
+    Setting { key: "file.path", flag: "file-path", kind: Kind::Text, help: "Directory to archive measurements in" },
+    Setting { key: "file.max_size_mb", flag: "file-max-size-mb", kind: Kind::Integer, help: "Size in MB at which the archive file is rotated" },
+    Setting { key: "file.rotate_secs", flag: "file-rotate-secs", kind: Kind::Integer, help: "Age in seconds at which the archive file is rotated" },
+    Setting { key: "file.gzip", flag: "file-gzip", kind: Kind::Boolean, help: "Compress rotated archive files" },
+    Setting { key: "file.keep", flag: "file-keep", kind: Kind::Integer, help: "Number of rotated archive files to keep" },
+    Setting { key: "forward.host", flag: "forward-host", kind: Kind::Text, help: "Aggregator to forward measurements to" },
+    Setting { key: "forward.port", flag: "forward-port", kind: Kind::Integer, help: "Aggregator port" },
+    Setting { key: "forward.token", flag: "forward-token", kind: Kind::Text, help: "Token the aggregator expects, prefer --forward-token-file" },
+    Setting { key: "forward.token_file", flag: "forward-token-file", kind: Kind::Text, help: "File to read the aggregator's token from" },
+    Setting { key: "forward.token_env", flag: "forward-token-env", kind: Kind::Text, help: "Environment variable to read the aggregator's token from" },
+    Setting { key: "otlp.host", flag: "otlp-host", kind: Kind::Text, help: "OpenTelemetry collector host" },
+    Setting { key: "otlp.port", flag: "otlp-port", kind: Kind::Integer, help: "OpenTelemetry collector OTLP/HTTP port" },
+    Setting { key: "otlp.path", flag: "otlp-path", kind: Kind::Text, help: "Path the collector receives metrics on" },
+    Setting { key: "aggregator.listen", flag: "aggregator-listen", kind: Kind::Text, help: "Address and port to listen for agents on" },
+    Setting { key: "aggregator.dedupe_secs", flag: "aggregator-dedupe-secs", kind: Kind::Integer, help: "Keep one monitor measurement per cluster this often" },
+    Setting { key: "aggregator.token", flag: "aggregator-token", kind: Kind::Text, help: "Token agents have to present, prefer --aggregator-token-file" },
+    Setting { key: "aggregator.token_file", flag: "aggregator-token-file", kind: Kind::Text, help: "File to read the token agents have to present from" },
+    Setting { key: "aggregator.token_env", flag: "aggregator-token-env", kind: Kind::Text, help: "Environment variable to read the token agents have to present from" },
+    Setting { key: "aggregator.max_agents", flag: "aggregator-max-agents", kind: Kind::Integer, help: "Most agents connected at once" },
+    Setting { key: "influx_udp.host", flag: "influx-udp-host", kind: Kind::Text, help: "InfluxDB UDP listener host" },
+    Setting { key: "influx_udp.port", flag: "influx-udp-port", kind: Kind::Integer, help: "InfluxDB UDP listener port" },
+    Setting { key: "influx_udp.precision", flag: "influx-udp-precision", kind: Kind::Text, help: "InfluxDB UDP listener precision: ns, us, ms or s" },
+    Setting { key: "influx.host", flag: "influx-host", kind: Kind::Text, help: "InfluxDB host" },
+    Setting { key: "influx.port", flag: "influx-port", kind: Kind::Integer, help: "InfluxDB port" },
+    Setting { key: "influx.user", flag: "influx-user", kind: Kind::Text, help: "InfluxDB user" },
+    Setting { key: "influx.password", flag: "influx-password", kind: Kind::Text, help: "InfluxDB password, prefer --influx-password-file" },
+    Setting { key: "influx.password_file", flag: "influx-password-file", kind: Kind::Text, help: "File to read the InfluxDB password from" },
+    Setting { key: "influx.password_env", flag: "influx-password-env", kind: Kind::Text, help: "Environment variable to read the InfluxDB password from" },
+    Setting { key: "influx.api", flag: "influx-api", kind: Kind::Text, help: "InfluxDB write API: v1 or v2" },
+    Setting { key: "influx.database", flag: "influx-database", kind: Kind::Text, help: "InfluxDB 1.x database" },
+    Setting { key: "influx.retention_policy", flag: "influx-retention-policy", kind: Kind::Text, help: "InfluxDB 1.x retention policy" },
+    Setting { key: "influx.bucket", flag: "influx-bucket", kind: Kind::Text, help: "InfluxDB 2.x bucket" },
+    Setting { key: "influx.org", flag: "influx-org", kind: Kind::Text, help: "InfluxDB 2.x organization" },
+    Setting { key: "influx.token", flag: "influx-token", kind: Kind::Text, help: "InfluxDB 2.x token, prefer --influx-token-file" },
+    Setting { key: "influx.token_file", flag: "influx-token-file", kind: Kind::Text, help: "File to read the InfluxDB 2.x token from" },
+    Setting { key: "influx.token_env", flag: "influx-token-env", kind: Kind::Text, help: "Environment variable to read the InfluxDB 2.x token from" },
+    Setting { key: "influx.precision", flag: "influx-precision", kind: Kind::Text, help: "InfluxDB timestamp precision: ns, us, ms or s" },
+    Setting { key: "influx.https", flag: "influx-https", kind: Kind::Boolean, help: "Connect to InfluxDB over HTTPS" },
+    Setting { key: "influx.ca_file", flag: "influx-ca-file", kind: Kind::Text, help: "CA certificate to check InfluxDB's against" },
+    Setting { key: "influx.cert_file", flag: "influx-cert-file", kind: Kind::Text, help: "Client certificate for InfluxDB" },
+    Setting { key: "influx.key_file", flag: "influx-key-file", kind: Kind::Text, help: "Client certificate key for InfluxDB" },
+    Setting { key: "influx.batch_size", flag: "influx-batch-size", kind: Kind::Integer, help: "Points to send to InfluxDB in one request" },
+    Setting { key: "influx.flush_ms", flag: "influx-flush-ms", kind: Kind::Integer, help: "Longest a point waits to be sent to InfluxDB" },
+    Setting { key: "influx.spool_path", flag: "influx-spool-path", kind: Kind::Text, help: "Directory to keep points in while InfluxDB is unavailable" },
+    Setting { key: "influx.spool_max_mb", flag: "influx-spool-max-mb", kind: Kind::Integer, help: "Size in MB at which the oldest spooled points are dropped" },
+    Setting { key: "hostname", flag: "hostname", kind: Kind::Text, help: "Hostname to tag measurements with instead of the system's" },
+    Setting { key: "fqdn", flag: "fqdn", kind: Kind::Boolean, help: "Tag measurements with the fully qualified hostname" },
+    Setting { key: "tags", flag: "tags", kind: Kind::Map, help: "Comma separated key=value tags added to every measurement" },
+    Setting { key: "ceph.cluster", flag: "ceph-cluster", kind: Kind::Text, help: "Name of the Ceph cluster" },
+    Setting { key: "ceph.admin_socket", flag: "ceph-admin-socket", kind: Kind::Text, help: "Admin socket path template, as in ceph.conf" },
+    Setting { key: "ceph.mon_data", flag: "ceph-mon-data", kind: Kind::Text, help: "Monitor data directory template, as in ceph.conf" },
+    Setting { key: "ceph.osd_data", flag: "ceph-osd-data", kind: Kind::Text, help: "OSD data directory template, as in ceph.conf" },
+    Setting { key: "collectors.monitor.enabled", flag: "collectors-monitor-enabled", kind: Kind::Boolean, help: "Poll Ceph monitors on this host" },
+    Setting { key: "collectors.monitor.interval_ms", flag: "collectors-monitor-interval-ms", kind: Kind::Integer, help: "Milliseconds between monitor polls" },
+    Setting { key: "collectors.monitor.rediscover_every", flag: "collectors-monitor-rediscover-every", kind: Kind::Integer, help: "Polls between checks for a monitor on this host" },
+    Setting { key: "collectors.monitor.perf_dump", flag: "collectors-monitor-perf-dump", kind: Kind::Text, help: "Monitor perf counters to send: curated or full" },
+    Setting { key: "collectors.monitor.rates", flag: "collectors-monitor-rates", kind: Kind::Boolean, help: "Also send monitor counter rates between polls" },
+    Setting { key: "collectors.osd.enabled", flag: "collectors-osd-enabled", kind: Kind::Boolean, help: "Poll Ceph OSDs on this host" },
+    Setting { key: "collectors.osd.interval_ms", flag: "collectors-osd-interval-ms", kind: Kind::Integer, help: "Milliseconds between OSD polls" },
+    Setting { key: "collectors.osd.rediscover_every", flag: "collectors-osd-rediscover-every", kind: Kind::Integer, help: "Polls between searches for new OSDs" },
+    Setting { key: "collectors.osd.perf_dump", flag: "collectors-osd-perf-dump", kind: Kind::Text, help: "OSD perf counters to send: curated or full" },
+    Setting { key: "collectors.osd.rates", flag: "collectors-osd-rates", kind: Kind::Boolean, help: "Also send OSD counter rates and interval latencies between polls" },
+    Setting { key: "collectors.packets.enabled", flag: "collectors-packets-enabled", kind: Kind::Boolean, help: "Sniff Ceph traffic on this host" },
+];
+
+pub fn setting(key: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|s| s.key == key)
+}
+
+impl Setting {
+    pub fn env_var(&self) -> String {
+        format!("ADMIN_CEPH_{}", self.key.replace(".", "_").to_uppercase())
+    }
+}
+
+impl Kind {
+    // Values that don't parse are passed through as strings so validation
+    // can report them against the key
+    fn to_yaml(&self, value: &str) -> Yaml {
+        match *self {
+            Kind::Text => Yaml::String(value.to_string()),
+            Kind::Integer => {
+                match value.trim().parse::<i64>() {
+                    Ok(i) => Yaml::Integer(i),
+                    Err(_) => Yaml::String(value.to_string()),
+                }
+            },
+            Kind::Boolean => {
+                match value.trim() {
+                    "true" => Yaml::Boolean(true),
+                    "false" => Yaml::Boolean(false),
+                    _ => Yaml::String(value.to_string()),
+                }
+            },
+            Kind::List => {
+                Yaml::Array(value.split(',')
+                                 .map(|v| v.trim())
+                                 .filter(|v| !v.is_empty())
+                                 .map(|v| Yaml::String(v.to_string()))
+                                 .collect())
+            },
+            Kind::Map => {
+                let mut hash = BTreeMap::new();
+                for pair in value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+                    let mut parts = pair.splitn(2, '=');
+                    let key = parts.next().unwrap_or("").trim();
+                    match parts.next() {
+                        Some(v) => hash.insert(Yaml::String(key.to_string()), Yaml::String(v.trim().to_string())),
+                        None => hash.insert(Yaml::String(key.to_string()), Yaml::Null),
+                    };
+                }
+                Yaml::Hash(hash)
+            },
+        }
+    }
+}
+
+/// A single value taken from outside the config file.  `source` names the
+/// flag or environment variable for error messages.
+#[derive(Clone)]
+pub struct Override {
+    pub setting: &'static Setting,
+    pub value: String,
+    pub source: String,
+}
+
+/// Everything the configuration is built from.  Overrides are applied in
+/// order on top of the file, so later ones win.
+#[derive(Clone)]
+pub struct Sources {
+    pub config_path: String,
+    // Set when the path was given explicitly rather than defaulted
+    pub path_required: bool,
+    pub overrides: Vec<Override>,
+    pub log_level: LogLevel,
+}
+
+impl Sources {
+    /// Collect every `ADMIN_CEPH_*` variable that names a known setting
+    pub fn add_env_overrides(&mut self) {
+        for setting in SETTINGS.iter() {
+            let var = setting.env_var();
+            if let Ok(value) = env::var(&var) {
+                self.overrides.push(Override {
+                    setting: setting,
+                    value: value,
+                    source: format!("environment variable {}", var),
+                });
+            }
+        }
+    }
+}
+
+/// The live configuration shared between every collector thread.  A reload
+/// swaps in a whole new `Args` and bumps the generation so that threads
+/// holding on to a copy know to pick up the new one.
+pub struct SharedArgs {
+    args: RwLock<Args>,
+    generation: AtomicUsize,
+}
+
+impl SharedArgs {
+    pub fn new(args: Args) -> Arc<SharedArgs> {
+        Arc::new(SharedArgs {
+            args: RwLock::new(args),
+            generation: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn current(&self) -> Args {
+        self.args.read().unwrap().clone()
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn replace(&self, args: Args) {
+        *self.args.write().unwrap() = args;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Block until the config has been reloaded since `generation`
+    pub fn wait_for_reload(&self, generation: usize) {
+        while self.generation() == generation {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// Describes why a config file was rejected, pointing at the offending
+/// file, line and dotted key wherever they are known.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: String,
+    pub line: Option<usize>,
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.file));
+        if let Some(line) = self.line {
+            try!(write!(f, ":{}", line));
+        }
+        if let Some(ref key) = self.key {
+            try!(write!(f, ": {}", key));
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Read and validate the configuration described by `sources`.  The file is
+/// optional only when it wasn't asked for explicitly, in which case the
+/// environment and command line have to provide everything; the agent should
+/// never run unconfigured.
+pub fn load(sources: &Sources) -> Result<Args, ConfigError> {
+    let config_path = &sources.config_path[..];
+    let yaml_text = match read_from_file(config_path) {
+        Ok(text) => text,
+        Err(_) if !sources.path_required && !sources.overrides.is_empty() => {
+            debug!("No config file at {}, using overrides only", config_path);
+            String::new()
+        },
+        Err(e) => {
+            return Err(ConfigError {
+                file: config_path.to_string(),
+                line: None,
+                key: None,
+                message: e,
+            });
+        }
+    };
+    parse(yaml_text.as_ref(), config_path, &sources.overrides, sources.log_level)
+}
+
+pub fn parse(args_string: &str, config_path: &str, overrides: &[Override], log_level: LogLevel) -> Result<Args, ConfigError> {
+    let doc = Document {
+        file: config_path,
+        text: args_string,
+        overrides: overrides,
+    };
+
+    let docs = try!(YamlLoader::load_from_str(&args_string).map_err(|e| {
+        // yaml_rust doesn't expose the error position directly, but its
+        // Debug output carries both the message and the marker
+        doc.error(None, format!("cannot load yaml: {:?}", e))
+    }));
+    let mut root = docs.into_iter().next().unwrap_or(Yaml::Hash(BTreeMap::new()));
+    for o in overrides {
+        // Whichever way a secret was given in the file, the override
+        // replaces it rather than conflicting with it
+        if let Some(base) = secret_base(o.setting.key) {
+            for sibling in [base.to_string(), format!("{}_file", base), format!("{}_env", base)].iter() {
+                if sibling != o.setting.key {
+                    let path: Vec<&str> = sibling.split('.').collect();
+                    remove_key(&mut root, &path);
+                }
+            }
+        }
+        let path: Vec<&str> = o.setting.key.split('.').collect();
+        if !set_key(&mut root, &path, o.setting.kind.to_yaml(&o.value)) {
+            return Err(doc.error(Some(o.setting.key),
+                                 "can't be overridden when the config file has a list here, set it there instead".to_string()));
+        }
+    }
+    let root = &root;
+    try!(doc.check_keys("", root, &["mode", "outputs", "stdout", "prometheus", "graphite", "statsd", "opentsdb",
+                                        "file", "forward", "otlp", "aggregator", "influx", "influx_udp", "routes",
+                                        "collectors", "ceph", "hostname", "fqdn", "tags"]));
+
+    let stdout_doc = &root["stdout"];
+    try!(doc.check_keys("stdout", stdout_doc, &["format", "stream"]));
+    let default = Stdout::default();
+    let stdout = Stdout {
+        format: match try!(doc.choice(stdout_doc, "stdout", "format", &["json", "line_protocol"])) {
+            Some("line_protocol") => Format::LineProtocol,
+            Some(_) => Format::Json,
+            None => default.format,
+        },
+        stream: match try!(doc.choice(stdout_doc, "stdout", "stream", &["stdout", "stderr"])) {
+            Some("stderr") => Stream::Stderr,
+            Some(_) => Stream::Stdout,
+            None => default.stream,
+        },
+    };
+
+    let outputs = try!(doc.string_list(root, "", "outputs")).unwrap_or(Vec::new());
+    for (i, output) in outputs.iter().enumerate() {
+        if !OUTPUTS.contains(&&output[..]) {
+            return Err(doc.error(Some(&format!("outputs.{}", i)),
+                                 format!("unknown output '{}', expected one of {:?}", output, OUTPUTS)));
+        }
+    }
+    if outputs.is_empty() {
+        return Err(doc.error(Some("outputs"), "no outputs configured, nothing would be logged".to_string()));
+    }
+
+    let prometheus_doc = &root["prometheus"];
+    try!(doc.check_keys("prometheus", prometheus_doc, &["listen"]));
+    let prometheus = Prometheus {
+        listen: try!(doc.string(prometheus_doc, "prometheus", "listen")).unwrap_or(Prometheus::default().listen),
+    };
+    if prometheus.listen.parse::<SocketAddr>().is_err() {
+        return Err(doc.error(Some("prometheus.listen"),
+                             format!("expected an address and port such as 0.0.0.0:9128, found '{}'", prometheus.listen)));
+    }
+
+    let graphite_doc = &root["graphite"];
+    try!(doc.check_keys("graphite", graphite_doc, &["host", "port", "protocol", "templates"]));
+    let protocol = match try!(doc.choice(graphite_doc, "graphite", "protocol", &["plaintext", "pickle"])) {
+        Some("pickle") => GraphiteProtocol::Pickle,
+        _ => GraphiteProtocol::Plaintext,
+    };
+    let mut templates = Graphite::default_templates();
+    for (measurement, template) in try!(doc.templates(&graphite_doc["templates"], "graphite.templates")) {
+        templates.insert(measurement, template);
+    }
+    let graphite = Graphite {
+        host: try!(doc.string(graphite_doc, "graphite", "host")).unwrap_or("127.0.0.1".to_string()),
+        port: try!(doc.port(graphite_doc, "graphite", "port")).unwrap_or(protocol.default_port()),
+        protocol: protocol,
+        templates: templates,
+    };
+
+    let statsd_doc = &root["statsd"];
+    try!(doc.check_keys("statsd", statsd_doc, &["host", "port", "dogstatsd", "prefix"]));
+    let default = Statsd::default();
+    let statsd = Statsd {
+        host: try!(doc.string(statsd_doc, "statsd", "host")).unwrap_or(default.host),
+        port: try!(doc.port(statsd_doc, "statsd", "port")).unwrap_or(default.port),
+        dogstatsd: try!(doc.boolean(statsd_doc, "statsd", "dogstatsd")).unwrap_or(default.dogstatsd),
+        prefix: try!(doc.string(statsd_doc, "statsd", "prefix")).unwrap_or(default.prefix),
+    };
+
+    let opentsdb_doc = &root["opentsdb"];
+    try!(doc.check_keys("opentsdb", opentsdb_doc, &["host", "port", "protocol", "prefix"]));
+    let default = OpenTsdb::default();
+    let opentsdb = OpenTsdb {
+        host: try!(doc.string(opentsdb_doc, "opentsdb", "host")).unwrap_or(default.host),
+        port: try!(doc.port(opentsdb_doc, "opentsdb", "port")).unwrap_or(default.port),
+        protocol: match try!(doc.choice(opentsdb_doc, "opentsdb", "protocol", &["http", "telnet"])) {
+            Some("telnet") => OpenTsdbProtocol::Telnet,
+            Some(_) => OpenTsdbProtocol::Http,
+            None => default.protocol,
+        },
+        prefix: try!(doc.string(opentsdb_doc, "opentsdb", "prefix")).unwrap_or(default.prefix),
+    };
+
+    let file_doc = &root["file"];
+    try!(doc.check_keys("file", file_doc, &["path", "max_size_mb", "rotate_secs", "gzip", "keep"]));
+    let default = Archive::default();
+    let file = Archive {
+        path: try!(doc.string(file_doc, "file", "path")).unwrap_or(default.path),
+        max_size_mb: try!(doc.positive_integer(file_doc, "file", "max_size_mb")).unwrap_or(default.max_size_mb),
+        rotate_secs: try!(doc.positive_integer(file_doc, "file", "rotate_secs")).unwrap_or(default.rotate_secs),
+        gzip: try!(doc.boolean(file_doc, "file", "gzip")).unwrap_or(default.gzip),
+        keep: try!(doc.positive_integer(file_doc, "file", "keep")).unwrap_or(default.keep),
+    };
+
+    let influx_doc = &root["influx"];
+    try!(doc.check_keys("influx", influx_doc, &["host", "port", "user", "password", "password_file", "password_env",
+                                                   "api", "database", "retention_policy", "bucket", "org",
+                                                   "token", "token_file", "token_env", "precision",
+                                                   "https", "ca_file", "cert_file", "key_file",
+                                                   "batch_size", "flush_ms", "spool_path", "spool_max_mb"]));
+    let default = Influx::default();
+    let api = match try!(doc.choice(influx_doc, "influx", "api", &["v1", "v2"])) {
+        Some("v2") => InfluxApi::V2,
+        _ => default.api,
+    };
+    let influx = Influx {
+        host: try!(doc.string(influx_doc, "influx", "host")).unwrap_or(default.host),
+        port: try!(doc.port(influx_doc, "influx", "port")).unwrap_or(default.port),
+        password: try!(doc.secret(influx_doc, "influx", "password")),
+        user: try!(doc.string(influx_doc, "influx", "user")).unwrap_or(default.user),
+        api: api,
+        database: try!(doc.string(influx_doc, "influx", "database")).unwrap_or(default.database),
+        retention_policy: try!(doc.string(influx_doc, "influx", "retention_policy")),
+        bucket: try!(doc.string(influx_doc, "influx", "bucket")).unwrap_or(default.bucket),
+        org: try!(doc.string(influx_doc, "influx", "org")),
+        token: try!(doc.secret(influx_doc, "influx", "token")),
+        precision: try!(doc.precision(influx_doc, "influx")).unwrap_or(default.precision),
+        https: try!(doc.boolean(influx_doc, "influx", "https")).unwrap_or(default.https),
+        ca_file: try!(doc.string(influx_doc, "influx", "ca_file")),
+        cert_file: try!(doc.string(influx_doc, "influx", "cert_file")),
+        key_file: try!(doc.string(influx_doc, "influx", "key_file")),
+        batch_size: try!(doc.positive_integer(influx_doc, "influx", "batch_size")).unwrap_or(default.batch_size),
+        flush_ms: try!(doc.positive_integer(influx_doc, "influx", "flush_ms")).unwrap_or(default.flush_ms),
+        spool_path: try!(doc.string(influx_doc, "influx", "spool_path")).unwrap_or(default.spool_path),
+        spool_max_mb: try!(doc.positive_integer(influx_doc, "influx", "spool_max_mb")).unwrap_or(default.spool_max_mb),
+    };
+    match influx.api {
+        InfluxApi::V1 => {
+            for key in ["bucket", "org", "token", "token_file", "token_env"].iter() {
+                if is_set(&influx_doc[*key]) {
+                    return Err(doc.error(Some(&join("influx", key)), "only used with api: v2".to_string()));
+                }
+            }
+        },
+        InfluxApi::V2 => {
+            for key in ["database", "retention_policy", "user", "password", "password_file", "password_env"].iter() {
+                if is_set(&influx_doc[*key]) {
+                    return Err(doc.error(Some(&join("influx", key)), "only used with api: v1, 2.x takes a token".to_string()));
+                }
+            }
+            if influx.org.is_none() {
+                return Err(doc.error(Some("influx.org"), "required with api: v2".to_string()));
+            }
+            if influx.token.is_none() {
+                return Err(doc.error(Some("influx.token"), "required with api: v2".to_string()));
+            }
+        },
+    }
+    for key in ["ca_file", "cert_file", "key_file"].iter() {
+        if !influx.https && is_set(&influx_doc[*key]) {
+            return Err(doc.error(Some(&join("influx", key)), "only used with https: true".to_string()));
+        }
+    }
+    if influx.cert_file.is_some() != influx.key_file.is_some() {
+        return Err(doc.error(Some(if influx.cert_file.is_some() { "influx.key_file" } else { "influx.cert_file" }),
+                             "cert_file and key_file have to be set together".to_string()));
+    }
+
+    let mode = match try!(doc.choice(root, "", "mode", &["agent", "aggregator"])) {
+        Some("aggregator") => Mode::Aggregator,
+        _ => Mode::Agent,
+    };
+
+    let forward_doc = &root["forward"];
+    try!(doc.check_keys("forward", forward_doc, &["host", "port", "token", "token_file", "token_env"]));
+    let default = Forward::default();
+    let forward = Forward {
+        host: try!(doc.string(forward_doc, "forward", "host")).unwrap_or(default.host),
+        port: try!(doc.port(forward_doc, "forward", "port")).unwrap_or(default.port),
+        token: try!(doc.secret(forward_doc, "forward", "token")),
+    };
+
+    let otlp_doc = &root["otlp"];
+    try!(doc.check_keys("otlp", otlp_doc, &["host", "port", "path"]));
+    let default = Otlp::default();
+    let otlp = Otlp {
+        host: try!(doc.string(otlp_doc, "otlp", "host")).unwrap_or(default.host),
+        port: try!(doc.port(otlp_doc, "otlp", "port")).unwrap_or(default.port),
+        path: try!(doc.string(otlp_doc, "otlp", "path")).unwrap_or(default.path),
+    };
+    if !otlp.path.starts_with("/") {
+        return Err(doc.error(Some("otlp.path"), format!("expected a path starting with /, found '{}'", otlp.path)));
+    }
+
+    let aggregator_doc = &root["aggregator"];
+    try!(doc.check_keys("aggregator", aggregator_doc, &["listen", "dedupe_secs", "token", "token_file", "token_env",
+                                                        "max_agents"]));
+    let default = Aggregator::default();
+    let aggregator = Aggregator {
+        listen: try!(doc.string(aggregator_doc, "aggregator", "listen")).unwrap_or(default.listen),
+        dedupe_secs: try!(doc.positive_integer(aggregator_doc, "aggregator", "dedupe_secs")).unwrap_or(default.dedupe_secs),
+        token: try!(doc.secret(aggregator_doc, "aggregator", "token")),
+        max_agents: try!(doc.positive_integer(aggregator_doc, "aggregator", "max_agents")).unwrap_or(default.max_agents),
+    };
+    match aggregator.listen.parse::<SocketAddr>() {
+        Ok(addr) => {
+            // Anyone who can reach it could send measurements otherwise
+            if mode == Mode::Aggregator && aggregator.token.is_none() && !is_loopback(&addr) {
+                return Err(doc.error(Some("aggregator.listen"),
+                                     "a token is required to listen beyond the loopback".to_string()));
+            }
+        },
+        Err(_) => {
+            return Err(doc.error(Some("aggregator.listen"),
+                                 format!("expected an address and port such as 0.0.0.0:9127, found '{}'", aggregator.listen)));
+        },
+    }
+
+    let influx_udp_doc = &root["influx_udp"];
+    try!(doc.check_keys("influx_udp", influx_udp_doc, &["host", "port", "precision"]));
+    let default = InfluxUdp::default();
+    let influx_udp = InfluxUdp {
+        host: try!(doc.string(influx_udp_doc, "influx_udp", "host")).unwrap_or(default.host),
+        port: try!(doc.port(influx_udp_doc, "influx_udp", "port")).unwrap_or(default.port),
+        precision: try!(doc.precision(influx_udp_doc, "influx_udp")).unwrap_or(default.precision),
+    };
+
+    let collectors_doc = &root["collectors"];
+    try!(doc.check_keys("collectors", collectors_doc, &["monitor", "osd", "packets"]));
+    let collectors = Collectors {
+        monitor: try!(doc.collector(collectors_doc, "monitor", true)),
+        osd: try!(doc.collector(collectors_doc, "osd", true)),
+        packets: try!(doc.collector(collectors_doc, "packets", false)),
+    };
+
+    let clusters = try!(doc.clusters(&root["ceph"], "ceph"));
+
+    let fqdn = try!(doc.boolean(root, "", "fqdn")).unwrap_or(false);
+    let hostname = match try!(doc.string(root, "", "hostname")) {
+        Some(name) => name,
+        None => {
+            try!(hostname::hostname(fqdn).map_err(|e| {
+                doc.error(Some("hostname"), format!("unable to determine the hostname, set it explicitly: {}", e))
+            }))
+        }
+    };
+    let tags = try!(doc.tags(&root["tags"], "tags"));
+    let routes = try!(doc.routes(&root["routes"], "routes"));
+
+    Ok(Args {
+        stdout: stdout,
+        prometheus: prometheus,
+        graphite: graphite,
+        statsd: statsd,
+        opentsdb: opentsdb,
+        file: file,
+        influx_udp: influx_udp,
+        forward: forward,
+        otlp: otlp,
+        aggregator: aggregator,
+        mode: mode,
+        influx: Some(influx),
+        outputs: outputs,
+        routes: routes,
+        log_level: log_level,
+        config_path: config_path.to_string(),
+        hostname: hostname,
+        fqdn: fqdn,
+        tags: tags,
+        collectors: collectors,
+        clusters: clusters,
+    })
+}
+
+// Set a dotted key in the yaml tree, creating (or replacing) whatever
+// mappings are needed on the way down.  A list is never replaced, there's
+// no telling which of its items the key was meant for, so that's false.
+fn set_key(node: &mut Yaml, path: &[&str], value: Yaml) -> bool {
+    match *node {
+        Yaml::Hash(_) => {},
+        Yaml::Array(_) => return false,
+        _ => *node = Yaml::Hash(BTreeMap::new()),
+    }
+    if let Yaml::Hash(ref mut hash) = *node {
+        let key = Yaml::String(path[0].to_string());
+        if path.len() == 1 {
+            hash.insert(key, value);
+        } else {
+            let child = hash.entry(key).or_insert(Yaml::Hash(BTreeMap::new()));
+            return set_key(child, &path[1..], value);
+        }
+    }
+    true
+}
+
+// Remove a dotted key from the yaml tree, if it's there
+fn remove_key(node: &mut Yaml, path: &[&str]) {
+    if let Yaml::Hash(ref mut hash) = *node {
+        let key = Yaml::String(path[0].to_string());
+        if path.len() == 1 {
+            hash.remove(&key);
+        } else if let Some(child) = hash.get_mut(&key) {
+            remove_key(child, &path[1..]);
+        }
+    }
+}
+
+// The secret a setting is one of the three ways of giving, ie: `influx.password`
+// for `influx.password_env`
+fn secret_base(key: &str) -> Option<&str> {
+    let base = if key.ends_with("_file") {
+        &key[..key.len() - "_file".len()]
+    } else if key.ends_with("_env") {
+        &key[..key.len() - "_env".len()]
+    } else {
+        key
+    };
+    if setting(base).is_some() && setting(&format!("{}_file", base)).is_some() &&
+       setting(&format!("{}_env", base)).is_some() {
+        Some(base)
+    } else {
+        None
+    }
+}
+
+// The raw text of a config file, kept around so that errors can be traced
+// back to a line
+struct Document<'a> {
+    file: &'a str,
+    text: &'a str,
+    overrides: &'a [Override],
+}
+
+impl<'a> Document<'a> {
+    fn error(&self, key: Option<&str>, message: String) -> ConfigError {
+        // Values that were overridden get blamed on the flag or variable
+        // they came from rather than on the file
+        let overridden = key.and_then(|k| {
+            self.overrides.iter().rev().find(|o| {
+                k == o.setting.key || k.starts_with(&format!("{}.", o.setting.key))
+            })
+        });
+        if let Some(o) = overridden {
+            return ConfigError {
+                file: o.source.clone(),
+                line: None,
+                key: key.map(|k| k.to_string()),
+                message: message,
+            };
+        }
+        ConfigError {
+            file: self.file.to_string(),
+            line: key.and_then(|k| line_of(self.text, k)),
+            key: key.map(|k| k.to_string()),
+            message: message,
+        }
+    }
+
+    // Reject any key in a mapping that we don't know how to handle
+    fn check_keys(&self, path: &str, node: &Yaml, allowed: &[&str]) -> Result<(), ConfigError> {
+        match *node {
+            Yaml::Hash(ref hash) => {
+                for key in hash.keys() {
+                    match key.as_str() {
+                        Some(k) if allowed.contains(&k) => {},
+                        Some(k) => {
+                            return Err(self.error(Some(&join(path, k)),
+                                                  format!("unknown key, expected one of {:?}", allowed)));
+                        },
+                        None => {
+                            return Err(self.error(Some(path), format!("keys must be strings, found {:?}", key)));
+                        }
+                    }
+                }
+                Ok(())
+            },
+            Yaml::BadValue | Yaml::Null => Ok(()),
+            _ => Err(self.error(Some(path), "expected a mapping".to_string())),
+        }
+    }
+
+    fn string(&self, node: &Yaml, path: &str, key: &str) -> Result<Option<String>, ConfigError> {
+        match node[key] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            Yaml::String(ref s) => Ok(Some(s.clone())),
+            ref other => Err(self.error(Some(&join(path, key)), format!("expected a string, found {:?}", other))),
+        }
+    }
+
+    // A secret is given inline as `<key>`, or read from the file named by
+    // `<key>_file` or the environment variable named by `<key>_env`.  Only one
+    // may be set, and a reference to something that isn't there is an error.
+    fn secret(&self, node: &Yaml, path: &str, key: &str) -> Result<Option<Secret>, ConfigError> {
+        let file_key = format!("{}_file", key);
+        let env_key = format!("{}_env", key);
+        let inline = try!(self.string(node, path, key));
+        let file = try!(self.string(node, path, &file_key));
+        let var = try!(self.string(node, path, &env_key));
+        let set = [inline.is_some(), file.is_some(), var.is_some()].iter().filter(|&&b| b).count();
+        if set > 1 {
+            return Err(self.error(Some(&join(path, key)),
+                                  format!("only one of {}, {} and {} may be set", key, file_key, env_key)));
+        }
+        if let Some(file) = file {
+            let contents = try!(read_from_file(&file).map_err(|e| {
+                self.error(Some(&join(path, &file_key)), format!("unable to read {}: {}", file, e))
+            }));
+            let secret = contents.trim_right_matches(|c| c == '\n' || c == '\r').to_string();
+            if secret.is_empty() {
+                return Err(self.error(Some(&join(path, &file_key)), format!("{} is empty", file)));
+            }
+            return Ok(Some(Secret(secret)));
+        }
+        if let Some(var) = var {
+            return match env::var(&var) {
+                Ok(ref secret) if !secret.is_empty() => Ok(Some(Secret(secret.clone()))),
+                _ => Err(self.error(Some(&join(path, &env_key)), format!("environment variable {} is not set", var))),
+            };
+        }
+        Ok(inline.map(Secret))
+    }
+
+    // Line protocol timestamp precision, as InfluxDB abbreviates it
+    fn precision(&self, node: &Yaml, path: &str) -> Result<Option<Precision>, ConfigError> {
+        Ok(match try!(self.choice(node, path, "precision", &["ns", "us", "ms", "s"])) {
+            Some("ns") => Some(Precision::Nanoseconds),
+            Some("us") => Some(Precision::Microseconds),
+            Some("ms") => Some(Precision::Milliseconds),
+            Some(_) => Some(Precision::Seconds),
+            None => None,
+        })
+    }
+
+    // A string that has to be one of `choices`
+    fn choice(&self, node: &Yaml, path: &str, key: &str, choices: &[&'static str]) -> Result<Option<&'static str>, ConfigError> {
+        match try!(self.string(node, path, key)) {
+            None => Ok(None),
+            Some(value) => {
+                match choices.iter().find(|c| **c == value) {
+                    Some(c) => Ok(Some(*c)),
+                    None => Err(self.error(Some(&join(path, key)),
+                                           format!("unknown value '{}', expected one of {:?}", value, choices))),
+                }
+            }
+        }
+    }
+
+    fn port(&self, node: &Yaml, path: &str, key: &str) -> Result<Option<u16>, ConfigError> {
+        match node[key] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            Yaml::Integer(i) if i > 0 && i <= 65535 => Ok(Some(i as u16)),
+            ref other => Err(self.error(Some(&join(path, key)), format!("expected a port number, found {:?}", other))),
+        }
+    }
+
+    fn boolean(&self, node: &Yaml, path: &str, key: &str) -> Result<Option<bool>, ConfigError> {
+        match node[key] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            Yaml::Boolean(b) => Ok(Some(b)),
+            ref other => Err(self.error(Some(&join(path, key)), format!("expected true or false, found {:?}", other))),
+        }
+    }
+
+    fn positive_integer(&self, node: &Yaml, path: &str, key: &str) -> Result<Option<u64>, ConfigError> {
+        match node[key] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            Yaml::Integer(i) if i > 0 => Ok(Some(i as u64)),
+            ref other => Err(self.error(Some(&join(path, key)), format!("expected a positive integer, found {:?}", other))),
+        }
+    }
+
+    // `polled` collectors also take an interval and a rediscovery period
+    fn collector(&self, collectors: &Yaml, name: &str, polled: bool) -> Result<Collector, ConfigError> {
+        let path = join("collectors", name);
+        let node = &collectors[name];
+        if polled {
+            try!(self.check_keys(&path, node, &["enabled", "interval_ms", "rediscover_every", "perf_dump", "rates"]));
+        } else {
+            try!(self.check_keys(&path, node, &["enabled"]));
+        }
+        let default = Collector::default();
+        let collector = Collector {
+            enabled: try!(self.boolean(node, &path, "enabled")).unwrap_or(default.enabled),
+            interval_ms: try!(self.positive_integer(node, &path, "interval_ms")).unwrap_or(default.interval_ms),
+            rediscover_every: try!(self.positive_integer(node, &path, "rediscover_every")).unwrap_or(default.rediscover_every),
+            perf_dump: match try!(self.choice(node, &path, "perf_dump", &["curated", "full"])) {
+                Some("full") => PerfDump::Full,
+                _ => default.perf_dump,
+            },
+            rates: try!(self.boolean(node, &path, "rates")).unwrap_or(default.rates),
+        };
+        // None of the curated monitor fields are counters
+        if name == "monitor" && collector.rates && collector.perf_dump != PerfDump::Full {
+            return Err(self.error(Some(&join(&path, "rates")),
+                                  "needs perf_dump: full, the curated monitor fields have no rates".to_string()));
+        }
+        Ok(collector)
+    }
+
+    // Tag values may be written as any scalar but are always sent as strings
+    fn tags(&self, node: &Yaml, path: &str) -> Result<BTreeMap<String, String>, ConfigError> {
+        let mut tags = BTreeMap::new();
+        let hash = match *node {
+            Yaml::BadValue | Yaml::Null => return Ok(tags),
+            Yaml::Hash(ref hash) => hash,
+            ref other => return Err(self.error(Some(path), format!("expected a mapping, found {:?}", other))),
+        };
+        for (key, value) in hash.iter() {
+            let key = match key.as_str() {
+                Some(k) => k,
+                None => return Err(self.error(Some(path), format!("keys must be strings, found {:?}", key))),
+            };
+            let tag_path = join(path, key);
+            if RESERVED_TAGS.contains(&key) {
+                return Err(self.error(Some(&tag_path), format!("'{}' is set by the collectors and can't be overridden", key)));
+            }
+            let value = match *value {
+                Yaml::String(ref s) => s.clone(),
+                Yaml::Integer(i) => i.to_string(),
+                Yaml::Real(ref r) => r.clone(),
+                Yaml::Boolean(b) => b.to_string(),
+                ref other => return Err(self.error(Some(&tag_path), format!("expected a string, found {:?}", other))),
+            };
+            if value.is_empty() {
+                return Err(self.error(Some(&tag_path), "tag values can't be empty".to_string()));
+            }
+            tags.insert(key.to_string(), value);
+        }
+        Ok(tags)
+    }
+
+    // Routing rules keyed by output
+    fn routes(&self, node: &Yaml, path: &str) -> Result<BTreeMap<String, Route>, ConfigError> {
+        let mut routes = BTreeMap::new();
+        let hash = match *node {
+            Yaml::BadValue | Yaml::Null => return Ok(routes),
+            Yaml::Hash(ref hash) => hash,
+            ref other => return Err(self.error(Some(path), format!("expected a mapping, found {:?}", other))),
+        };
+        for (key, value) in hash.iter() {
+            let output = match key.as_str() {
+                Some(k) => k,
+                None => return Err(self.error(Some(path), format!("keys must be strings, found {:?}", key))),
+            };
+            let route_path = join(path, output);
+            if !OUTPUTS.contains(&output) {
+                return Err(self.error(Some(&route_path), format!("unknown output '{}', expected one of {:?}", output, OUTPUTS)));
+            }
+            try!(self.check_keys(&route_path, value, &["measurements", "fields", "rename_fields", "drop_tags"]));
+            let mut rename_fields = BTreeMap::new();
+            let renames_path = join(&route_path, "rename_fields");
+            match value["rename_fields"] {
+                Yaml::BadValue | Yaml::Null => {},
+                Yaml::Hash(ref renames) => {
+                    for (from, to) in renames.iter() {
+                        match (from.as_str(), to.as_str()) {
+                            (Some(from), Some(to)) if !to.is_empty() => {
+                                rename_fields.insert(from.to_string(), to.to_string());
+                            },
+                            _ => return Err(self.error(Some(&renames_path),
+                                                       format!("expected field names, found {:?}: {:?}", from, to))),
+                        }
+                    }
+                },
+                ref other => return Err(self.error(Some(&renames_path), format!("expected a mapping, found {:?}", other))),
+            }
+            routes.insert(output.to_string(), Route {
+                measurements: try!(self.filter(&value["measurements"], &join(&route_path, "measurements"))),
+                fields: try!(self.filter(&value["fields"], &join(&route_path, "fields"))),
+                rename_fields: rename_fields,
+                drop_tags: try!(self.string_list(value, &route_path, "drop_tags")).unwrap_or(Vec::new()),
+            });
+        }
+        Ok(routes)
+    }
+
+    // Include and exclude lists of regular expressions
+    fn filter(&self, node: &Yaml, path: &str) -> Result<Filter, ConfigError> {
+        try!(self.check_keys(path, node, &["include", "exclude"]));
+        Ok(Filter {
+            include: try!(self.patterns(node, path, "include")),
+            exclude: try!(self.patterns(node, path, "exclude")),
+        })
+    }
+
+    fn patterns(&self, node: &Yaml, path: &str, key: &str) -> Result<Vec<String>, ConfigError> {
+        let patterns = try!(self.string_list(node, path, key)).unwrap_or(Vec::new());
+        for pattern in patterns.iter() {
+            if let Err(e) = Regex::new(pattern) {
+                return Err(self.error(Some(&join(path, key)), format!("invalid pattern '{}': {}", pattern, e)));
+            }
+        }
+        Ok(patterns)
+    }
+
+    // Metric path templates keyed by measurement, every `{` closed by a `}`
+    fn templates(&self, node: &Yaml, path: &str) -> Result<BTreeMap<String, String>, ConfigError> {
+        let mut templates = BTreeMap::new();
+        let hash = match *node {
+            Yaml::BadValue | Yaml::Null => return Ok(templates),
+            Yaml::Hash(ref hash) => hash,
+            ref other => return Err(self.error(Some(path), format!("expected a mapping, found {:?}", other))),
+        };
+        for (key, value) in hash.iter() {
+            let key = match key.as_str() {
+                Some(k) => k,
+                None => return Err(self.error(Some(path), format!("keys must be strings, found {:?}", key))),
+            };
+            let template_path = join(path, key);
+            let template = match value.as_str() {
+                Some(t) => t,
+                None => return Err(self.error(Some(&template_path), format!("expected a string, found {:?}", value))),
+            };
+            if !placeholders_balanced(template) {
+                return Err(self.error(Some(&template_path), "unbalanced braces or an empty {}".to_string()));
+            }
+            templates.insert(key.to_string(), template.to_string());
+        }
+        Ok(templates)
+    }
+
+    // `ceph:` is either a single cluster or a list of them
+    fn clusters(&self, node: &Yaml, path: &str) -> Result<Vec<Ceph>, ConfigError> {
+        let clusters = match *node {
+            Yaml::Array(ref items) => {
+                let mut clusters: Vec<Ceph> = Vec::new();
+                for (i, item) in items.iter().enumerate() {
+                    let item_path = format!("{}.{}", path, i);
+                    let ceph = try!(self.ceph(item, &item_path));
+                    if clusters.iter().any(|c| c.cluster == ceph.cluster) {
+                        return Err(self.error(Some(&join(&item_path, "cluster")),
+                                              format!("cluster '{}' is listed more than once", ceph.cluster)));
+                    }
+                    clusters.push(ceph);
+                }
+                clusters
+            },
+            _ => vec![try!(self.ceph(node, path))],
+        };
+        if clusters.is_empty() {
+            return Err(self.error(Some(path), "at least one cluster is needed".to_string()));
+        }
+        Ok(clusters)
+    }
+
+    fn ceph(&self, node: &Yaml, path: &str) -> Result<Ceph, ConfigError> {
+        try!(self.check_keys(path, node, &["cluster", "admin_socket", "mon_data", "osd_data"]));
+        let default = Ceph::default();
+        let ceph = Ceph {
+            cluster: try!(self.string(node, path, "cluster")).unwrap_or(default.cluster),
+            admin_socket: try!(self.string(node, path, "admin_socket")).unwrap_or(default.admin_socket),
+            mon_data: try!(self.string(node, path, "mon_data")).unwrap_or(default.mon_data),
+            osd_data: try!(self.string(node, path, "osd_data")).unwrap_or(default.osd_data),
+        };
+        if ceph.cluster.is_empty() || ceph.cluster.contains('/') {
+            return Err(self.error(Some(&join(path, "cluster")), "cluster names must be non-empty and can't contain '/'".to_string()));
+        }
+        // Discovery works backwards from the daemon's part of the file name
+        for &(key, template) in [("admin_socket", &ceph.admin_socket),
+                                 ("mon_data", &ceph.mon_data),
+                                 ("osd_data", &ceph.osd_data)].iter() {
+            let file_name = template.rsplit('/').next().unwrap_or("");
+            if !file_name.contains("$name") && !file_name.contains("$id") {
+                return Err(self.error(Some(&join(path, key)),
+                                      "the last path component must contain $name or $id".to_string()));
+            }
+            if template.rsplitn(2, '/').nth(1).map_or(false, |dir| dir.contains("$name") || dir.contains("$id")) {
+                return Err(self.error(Some(&join(path, key)),
+                                      "$name and $id may only appear in the last path component".to_string()));
+            }
+        }
+        Ok(ceph)
+    }
+
+    fn string_list(&self, node: &Yaml, path: &str, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+        let list_path = join(path, key);
+        match node[key] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            Yaml::Array(ref items) => {
+                let mut list = Vec::new();
+                for (i, item) in items.iter().enumerate() {
+                    match item.as_str() {
+                        Some(s) => list.push(s.to_string()),
+                        None => {
+                            return Err(self.error(Some(&format!("{}.{}", list_path, i)),
+                                                  format!("expected a string, found {:?}", item)));
+                        }
+                    }
+                }
+                Ok(Some(list))
+            },
+            ref other => Err(self.error(Some(&list_path), format!("expected a list, found {:?}", other))),
+        }
+    }
+}
+
+// Every `{` is closed by a `}` before the next one opens, with a name
+// in between
+fn placeholders_balanced(template: &str) -> bool {
+    let mut open = false;
+    for c in template.chars() {
+        match c {
+            '{' if !open => open = true,
+            '}' if open => open = false,
+            '{' | '}' => return false,
+            _ => {},
+        }
+    }
+    !open && !template.contains("{}")
+}
+
+fn is_loopback(addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V4(ref addr) => addr.ip().is_loopback(),
+        SocketAddr::V6(ref addr) => addr.ip().is_loopback(),
+    }
+}
+
+fn is_set(node: &Yaml) -> bool {
+    match *node {
+        Yaml::BadValue | Yaml::Null => false,
+        _ => true,
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+// Best effort search for the line a dotted key is defined on.  Each
+// segment has to appear, in order, below the line the previous segment was
+// found on: names as a `key:` and list indexes as the n'th `- ` item.  If
+// the whole key can't be found, the closest parent that could be is used.
+fn line_of(text: &str, key: &str) -> Option<usize> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut from = 0;
+    let mut found = None;
+    for segment in key.split('.') {
+        let is_index = segment.parse::<usize>().is_ok();
+        let next = if is_index {
+            let index = segment.parse::<usize>().unwrap();
+            (from..lines.len()).filter(|&i| lines[i].trim_left().starts_with('-')).nth(index)
+        } else {
+            (from..lines.len()).find(|&i| {
+                let trimmed = lines[i].trim_left().trim_left_matches('-').trim_left();
+                trimmed.starts_with(segment) && trimmed[segment.len()..].trim_left().starts_with(':')
+            })
+        };
+        match next {
+            Some(i) => {
+                found = Some(i + 1);
+                // A list item can start its mapping on the same line
+                from = if is_index { i } else { i + 1 };
+            },
+            None => break,
+        }
+    }
+    found
+}
+
+fn read_from_file(config_path: &str) -> Result<String, String> {
+    let mut f = try!(File::open(config_path).map_err(|e| e.to_string()));
+
+    let mut s = String::new();
+    try!(f.read_to_string(&mut s).map_err(|e| e.to_string()));
+    Ok(s.to_string())
+}
@@ -0,0 +1,95 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use regex::{self, Regex};
+
+use config::Ceph;
+
+#[cfg(test)]
+mod tests {
+    use config::Ceph;
+
+    #[test]
+    fn test_daemon_pattern() {
+        let ceph = Ceph::default();
+        let (dir, pattern) = super::daemon_pattern(&ceph, &ceph.admin_socket, "osd");
+        assert_eq!(dir, "/var/run/ceph");
+        assert_eq!(pattern.captures("ceph-osd.12.asok").unwrap().name("id"), Some("12"));
+        assert!(pattern.captures("ceph-mon.ip-172-31-22-89.asok").is_none());
+        assert!(pattern.captures("backup-osd.1.asok").is_none());
+
+        let (dir, pattern) = super::daemon_pattern(&ceph, &ceph.mon_data, "mon");
+        assert_eq!(dir, "/var/lib/ceph/mon");
+        assert_eq!(pattern.captures("ceph-ip-172-31-24-128").unwrap().name("id"), Some("ip-172-31-24-128"));
+    }
+}
+
+/// List the ids of every daemon of `daemon_type` with an entry matching one
+/// of the cluster's path templates, ie: the OSD numbers that have an admin
+/// socket.
+pub fn daemon_ids(ceph: &Ceph, template: &str, daemon_type: &str) -> Result<Vec<String>, io::Error> {
+    let (dir, pattern) = daemon_pattern(ceph, template, daemon_type);
+    let mut ids = Vec::new();
+    for entry in try!(fs::read_dir(Path::new(&dir))) {
+        let entry = try!(entry);
+        let file_name = entry.file_name();
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            //Skip files we can't turn into a string
+            None => continue,
+        };
+        if let Some(captures) = pattern.captures(name) {
+            if let Some(id) = captures.name("id") {
+                ids.push(id.to_string());
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+// Split a template into the directory to look in and a regex matching the
+// entries in it, with the daemon id captured as `id`
+fn daemon_pattern(ceph: &Ceph, template: &str, daemon_type: &str) -> (String, Regex) {
+    let expanded = template.replace("$cluster", &ceph.cluster).replace("$type", daemon_type);
+    let (dir, file_name) = match expanded.rfind('/') {
+        Some(0) => ("/", &expanded[1..]),
+        Some(i) => (&expanded[..i], &expanded[i + 1..]),
+        None => (".", &expanded[..]),
+    };
+    let mut pattern = String::from("^");
+    let mut captured = false;
+    let mut rest = file_name;
+    loop {
+        let next = ["$name", "$id"].iter()
+                                   .filter_map(|p| rest.find(*p).map(|i| (i, *p)))
+                                   .min();
+        match next {
+            Some((i, placeholder)) => {
+                pattern.push_str(&regex::quote(&rest[..i]));
+                if placeholder == "$name" {
+                    pattern.push_str(&regex::quote(daemon_type));
+                    pattern.push_str("\\.");
+                }
+                // Only the first occurrence is captured
+                if captured {
+                    pattern.push_str("[^/]+?");
+                } else {
+                    pattern.push_str("(?P<id>[^/]+?)");
+                    captured = true;
+                }
+                rest = &rest[i + placeholder.len()..];
+            },
+            None => {
+                pattern.push_str(&regex::quote(rest));
+                break;
+            }
+        }
+    }
+    pattern.push('$');
+    // Config validation guarantees the file name has an id in it, so this
+    // always compiles
+    let regex = Regex::new(&pattern).unwrap();
+    (dir.to_string(), regex)
+}
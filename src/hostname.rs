@@ -0,0 +1,51 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use libc;
+
+/// The name of this host from gethostname(2), or with `fqdn` its canonical
+/// name as the resolver sees it
+pub fn hostname(fqdn: bool) -> Result<String, String> {
+    let mut buf = vec![0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return Err(format!("gethostname failed with errno {}", errno()));
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    let host = try!(String::from_utf8(buf).map_err(|_| "hostname is not valid UTF-8".to_string()));
+    trace!("Got hostname: '{}'", host);
+    if fqdn {
+        canonical_name(&host)
+    } else {
+        Ok(host)
+    }
+}
+
+fn canonical_name(host: &str) -> Result<String, String> {
+    let node = try!(CString::new(host).map_err(|e| e.to_string()));
+    let mut hints: libc::addrinfo = unsafe { ::std::mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    let mut res: *mut libc::addrinfo = ptr::null_mut();
+    let ret = unsafe { libc::getaddrinfo(node.as_ptr(), ptr::null(), &hints, &mut res) };
+    if ret != 0 || res.is_null() {
+        return Err(format!("unable to resolve the FQDN of {}, getaddrinfo returned {}", host, ret));
+    }
+    let name = unsafe {
+        let canonname = (*res).ai_canonname;
+        let name = if canonname.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(canonname).to_string_lossy().into_owned())
+        };
+        libc::freeaddrinfo(res);
+        name
+    };
+    let name = try!(name.ok_or(format!("no canonical name found for {}", host)));
+    trace!("Got FQDN: '{}'", name);
+    Ok(name)
+}
+
+fn errno() -> i32 {
+    ::std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
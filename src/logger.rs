@@ -0,0 +1,28 @@
+use std::io::{self, Write};
+
+use log::{self, LogLevel, LogMetadata, LogRecord, SetLoggerError};
+
+// Logs go to stderr so that stdout is left for the `stdout` output
+struct StderrLogger {
+    level: LogLevel,
+}
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(&mut io::stderr(), "{:<5} [{}] {}",
+                             record.level(), record.location().module_path(), record.args());
+        }
+    }
+}
+
+pub fn init(level: LogLevel) -> Result<(), SetLoggerError> {
+    log::set_logger(|max_level| {
+        max_level.set(level.to_log_level_filter());
+        Box::new(StderrLogger { level: level })
+    })
+}
@@ -1,136 +1,94 @@
 extern crate ceph;
 #[macro_use] extern crate clap;
+extern crate flate2;
 extern crate hyper;
-extern crate influent;
+extern crate libc;
 #[macro_use] extern crate log;
+extern crate openssl;
 extern crate pcap;
 extern crate regex;
 extern crate rustc_serialize;
 extern crate serde;
 extern crate serde_json;
-extern crate simple_logger;
 extern crate time;
 extern crate yaml_rust;
 
-use std::fs::File;
 use std::io::prelude::*;
-use std::process::Command;
 
-use clap::{Arg, App};
-use log::LogLevel;
-use yaml_rust::YamlLoader;
+use clap::{Arg, App, SubCommand};
+
+use config::{Mode, Override, SharedArgs, Sources, SETTINGS};
 
 // mod messaging;
+mod admin_socket;
+mod aggregator;
 mod ceph_monitor;
 mod ceph_osd;
 mod ceph_packets;
-
-#[cfg(test)]
-mod tests{
-    use log::LogLevel;
-    #[test]
-    fn test_parse_file() {
-        let file = r#"
-outputs:
-  - stdout
-  - influx
-influx:
-  host: 127.0.0.1
-  port: 8086
-  user: root
-  password: root
-"#;
-        let args = super::parse(file, LogLevel::Info).unwrap();
-
-        assert_eq!(args.outputs, vec!["stdout", "influx"]);
-        assert_eq!(args.influx.unwrap().port, "8086");
-    }
-}
-
-#[derive(Clone,Debug)]
-pub struct Args {
-    pub influx: Option<Influx>,
-    pub stdout: Option<String>,
-    pub outputs: Vec<String>,
-    pub config_path: String,
-    pub log_level: log::LogLevel,
-    pub hostname: String,
-}
-
-struct CliArgs {
-    log_level: log::LogLevel,
-    config_file: String,
-}
-
-fn hostname() -> String{
-    let output = Command::new("hostname")
-                         .output()
-                         .unwrap_or_else(|e| panic!("failed to execute hostname: {}", e));
-    let host = match String::from_utf8(output.stdout) {
-        Ok(v) => v.replace("\n", ""),
-        Err(_) => "{}".to_string(),
-   };
-   trace!("Got hostname: '{}'", host);
-
-   host
+mod config;
+mod discovery;
+mod hostname;
+mod logger;
+mod metric;
+mod perf_dump;
+mod rates;
+mod reload;
+mod sinks;
+
+enum Command {
+    Run,
+    // Validate the config and exit
+    CheckConfig,
+    // Print the effective config, secrets redacted, and exit
+    PrintConfig,
 }
 
-impl Args {
-    fn clean() -> Args {
-        Args {
-            influx: None,
-            stdout: None,
-            outputs: Vec::new(),
-            config_path: "".to_string(),
-            log_level: LogLevel::Info,
-            hostname: hostname(),
+fn main() {
+    let (sources, command) = get_cli_args();
+    let args = match config::load(&sources) {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = writeln!(&mut std::io::stderr(), "Invalid configuration: {}", e);
+            std::process::exit(1);
         }
+    };
+    match command {
+        Command::CheckConfig => {
+            println!("{}: configuration OK", sources.config_path);
+            std::process::exit(0);
+        },
+        Command::PrintConfig => {
+            print!("{}", args.to_yaml_string());
+            std::process::exit(0);
+        },
+        Command::Run => {},
     }
-    fn with_log_level(log_level: LogLevel) -> Args {
-        Args {
-            influx: None,
-            stdout: None,
-            outputs: Vec::new(),
-            config_path: "".to_string(),
-            log_level: log_level,
-            hostname: hostname(),
+    logger::init(args.log_level).unwrap();
+    info!("Starting program");
+    info!("Logging with: {:?}", args);
+    reload::install_sighup_handler();
+    let shared = SharedArgs::new(args);
+    let metrics = sinks::initialize_pipeline(&shared);
+    if shared.current().mode == Mode::Aggregator {
+        if let Err(e) = aggregator::initialize_listener(&shared, metrics) {
+            error!("Unable to listen for agents: {}", e);
+            std::process::exit(1);
         }
+    } else {
+        ceph_monitor::initialize_monitor_scanner(&shared, metrics.clone());
+        ceph_packets::initialize_pcap(&shared, metrics.clone());
+        ceph_osd::initialize_osd_scanner(&shared, metrics);
     }
-}
-
-#[derive(Clone,Debug)]
-pub struct Influx {
-    pub user: String,
-    pub password: String,
-    pub host: String,
-    pub port: String
-}
-
-fn main() {
-    println!("Starting program");
-    let args = get_args();
-    simple_logger::init_with_level(args.log_level).unwrap();
-    info!("Logging with: {:?}", args);
-    ceph_monitor::initialize_monitor_scanner(&args);
-    ceph_packets::initialize_pcap(&args);
-    ceph_osd::initialize_osd_scanner(&args);
     loop {
-        std::thread::sleep(std::time::Duration::new(10, 0));
+        std::thread::sleep(std::time::Duration::new(1, 0));
+        reload::reload_if_requested(&shared, &sources);
     }
 }
 
-fn get_args() -> Args {
-    let cli_args = get_cli_args();
-    let yaml_text = match read_from_file(cli_args.config_file.as_ref()) {
-        Ok(yaml) => yaml,
-        Err(_) => "".to_string(),
-    };
-    let args = parse(yaml_text.as_ref(), cli_args.log_level).unwrap_or(Args::clean());
-    args
-}
-
-fn get_cli_args() -> CliArgs {
-    let matches = App::new("admin_ceph")
+// Precedence, lowest first: built in defaults, the config file,
+// ADMIN_CEPH_* environment variables, command line flags
+fn get_cli_args() -> (Sources, Command) {
+    let mut app = App::new("admin_ceph")
         .version(crate_version!())
         .arg(Arg::with_name("debug")
                            .short("d")
@@ -139,9 +97,19 @@ fn get_cli_args() -> CliArgs {
         .arg(Arg::with_name("CONFIG")
                            .short("c")
                            .long("config")
-                           .help("Sets a custom config file")
+                           .help("Sets a custom config file, also read from ADMIN_CEPH_CONFIG")
                            .takes_value(true))
-        .get_matches();
+        .subcommand(SubCommand::with_name("check-config")
+                           .about("Validates the configuration, exiting 0 if it is usable and 1 if not"))
+        .subcommand(SubCommand::with_name("print-config")
+                           .about("Prints the effective configuration with secrets redacted"));
+    for setting in SETTINGS.iter() {
+        app = app.arg(Arg::with_name(setting.flag)
+                           .long(setting.flag)
+                           .help(setting.help)
+                           .takes_value(true));
+    }
+    let matches = app.get_matches();
     // let matches = clap_app!(args =>
     //     (version: &version[..])
     //     (@arg CONFIG: -c --config +takes_value "Path to config file")
@@ -154,74 +122,30 @@ fn get_cli_args() -> CliArgs {
         2 => log::LogLevel::Debug,
         3 | _ => log::LogLevel::Trace,
     };
-    CliArgs {
-        log_level: log_level,
-        config_file: matches.value_of("CONFIG").unwrap_or("/etc/default/decode_ceph.yaml").to_string()
-    }
-}
-
-fn parse(args_string: &str, log_level: LogLevel) -> Result<Args, String> {
-    let config_path = "/etc/default/decode_ceph.yaml";
-
-    // Remove this hack when the new version of yaml_rust releases to get the real
-    // error msg
-    let docs = match YamlLoader::load_from_str(&args_string) {
-        Ok(data) => data,
-        Err(_) => {
-            // error!("Unable to load yaml data from config file");
-            return Err("cannot load data from yaml".to_string());
-        }
+    let config_file = match matches.value_of("CONFIG") {
+        Some(path) => Some(path.to_string()),
+        None => std::env::var("ADMIN_CEPH_CONFIG").ok(),
     };
-    if docs.len() == 0 {
-        return Ok(Args::with_log_level(log_level));
-    }
-    let doc = &docs[0];
-
-    let stdout = match doc["stdout"].as_str() {
-        Some(o) => Some(o.to_string()),
-        None => None
-    };
-    let influx_doc = doc["influx"].clone();
-    let influx_host = influx_doc["host"].as_str().unwrap_or("127.0.0.1");
-    let influx_port = influx_doc["port"].as_str().unwrap_or("8086");
-    let influx_password = influx_doc["password"].as_str().unwrap_or("root");
-    let influx_user = influx_doc["user"].as_str().unwrap_or("root");
-    let influx = Influx {
-        host: influx_host.to_string(),
-        port: influx_port.to_string(),
-        password: influx_password.to_string(),
-        user: influx_user.to_string(),
+    let mut sources = Sources {
+        path_required: config_file.is_some(),
+        config_path: config_file.unwrap_or(config::DEFAULT_CONFIG_PATH.to_string()),
+        overrides: Vec::new(),
+        log_level: log_level,
     };
-
-
-    let outputs: Vec<String> = match doc["outputs"].as_vec() {
-        Some(o) => {
-            o.iter()
-             .map(|x| {
-                 match x.as_str() {
-                     Some(o) => o.to_string(),
-                     None => "".to_string(),
-                 }
-             })
-             .collect()
+    sources.add_env_overrides();
+    for setting in SETTINGS.iter() {
+        if let Some(value) = matches.value_of(setting.flag) {
+            sources.overrides.push(Override {
+                setting: setting,
+                value: value.to_string(),
+                source: format!("--{}", setting.flag),
+            });
         }
-        None => Vec::new(),
+    }
+    let command = match matches.subcommand_name() {
+        Some("check-config") => Command::CheckConfig,
+        Some("print-config") => Command::PrintConfig,
+        _ => Command::Run,
     };
-
-    Ok(Args {
-        stdout: stdout,
-        influx: Some(influx),
-        outputs: outputs,
-        log_level: log_level,
-        config_path: config_path.to_string(),
-        hostname: hostname(),
-    })
-}
-
-fn read_from_file(config_path: &str) -> Result<String, String> {
-    let mut f = try!(File::open(config_path).map_err(|e| e.to_string()));
-
-    let mut s = String::new();
-    try!(f.read_to_string(&mut s).map_err(|e| e.to_string()));
-    Ok(s.to_string())
+    (sources, command)
 }
@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rustc_serialize::json::Json;
+use time::{self, Timespec};
+
+#[cfg(test)]
+mod tests {
+    use metric::{Metric, Precision, Value};
+    use time::Timespec;
+
+    fn metric() -> Metric {
+        let mut metric = Metric::new("osd operation");
+        metric.timestamp = Timespec::new(1458000000, 5);
+        metric.add_tag("hostname", "ceph-1");
+        metric.add_tag("rack", "a=1,b 2");
+        metric.add_field("count", Value::Integer(3));
+        metric.add_field("latency", Value::Float(0.5));
+        metric.add_field("operation", Value::String("\"WRITE\" \\ ONDISK".to_string()));
+        metric.add_field("up", Value::Boolean(true));
+        metric
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        assert_eq!(metric().to_line_protocol().unwrap(),
+                   "osd\\ operation,hostname=ceph-1,rack=a\\=1\\,b\\ 2 \
+                    count=3i,latency=0.5,operation=\"\\\"WRITE\\\" \\\\ ONDISK\",up=true \
+                    1458000000000000005");
+        let line = metric().to_line_protocol_in(Precision::Milliseconds).unwrap();
+        assert!(line.ends_with("up=true 1458000000000"));
+        let line = metric().to_line_protocol_in(Precision::Seconds).unwrap();
+        assert!(line.ends_with("up=true 1458000000"));
+
+        // Nothing Influx can store
+        let mut empty = Metric::new("osd_daemon");
+        assert_eq!(empty.to_line_protocol(), None);
+        empty.add_field("ratio", Value::Float(::std::f64::NAN));
+        assert_eq!(empty.to_line_protocol(), None);
+    }
+
+    #[test]
+    fn test_forward_json() {
+        let mut metric = metric();
+        metric.add_counter("ops", Value::Integer(4889));
+        metric.add_field("ratio", Value::Float(2.0));
+        let line = metric.to_forward_json();
+        let parsed = Metric::from_forward_json(&line).unwrap();
+        assert_eq!(parsed.name, metric.name);
+        assert_eq!(parsed.timestamp, metric.timestamp);
+        assert_eq!(parsed.tags, metric.tags);
+        assert_eq!(parsed.fields, metric.fields);
+        assert!(parsed.is_counter("ops"));
+        assert!(!parsed.is_counter("count"));
+
+        assert!(Metric::from_forward_json("{\"name\":\"osd_daemon\"}").is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(metric().to_json(),
+                   r#"{"fields":{"count":3,"latency":0.5,"operation":"\"WRITE\" \\ ONDISK","up":true},"#.to_string() +
+                   r#""name":"osd operation","tags":{"hostname":"ceph-1","rack":"a=1,b 2"},"timestamp":1458000000}"#);
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+}
+
+/// The unit of line protocol timestamps
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    /// As InfluxDB abbreviates it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Precision::Nanoseconds => "ns",
+            Precision::Microseconds => "us",
+            Precision::Milliseconds => "ms",
+            Precision::Seconds => "s",
+        }
+    }
+}
+
+/// One measurement from a collector, owned so it can be handed across
+/// threads to every configured sink
+#[derive(Clone,Debug)]
+pub struct Metric {
+    pub name: String,
+    pub timestamp: Timespec,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, Value>,
+    // Fields that only ever go up, ie: totals since the daemon started
+    pub counters: BTreeSet<String>,
+}
+
+impl Metric {
+    /// A new measurement, timestamped now
+    pub fn new(name: &str) -> Metric {
+        Metric {
+            name: name.to_string(),
+            timestamp: time::get_time(),
+            tags: BTreeMap::new(),
+            fields: BTreeMap::new(),
+            counters: BTreeSet::new(),
+        }
+    }
+
+    pub fn add_tag(&mut self, key: &str, value: &str) {
+        self.tags.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn add_field(&mut self, key: &str, value: Value) {
+        self.fields.insert(key.to_string(), value);
+    }
+
+    /// Add a field that is a running total rather than a reading
+    pub fn add_counter(&mut self, key: &str, value: Value) {
+        self.add_field(key, value);
+        self.counters.insert(key.to_string());
+    }
+
+    pub fn is_counter(&self, key: &str) -> bool {
+        self.counters.contains(key)
+    }
+
+    /// Influx line protocol, with a nanosecond timestamp
+    pub fn to_line_protocol(&self) -> Option<String> {
+        self.to_line_protocol_in(Precision::Nanoseconds)
+    }
+
+    /// Influx line protocol, with the timestamp truncated to `precision`.
+    /// A point needs at least one field, so there's no line for a
+    /// measurement without any that Influx can take.
+    pub fn to_line_protocol_in(&self, precision: Precision) -> Option<String> {
+        let mut line = escape(&self.name, &[',', ' ']);
+        for (key, value) in self.tags.iter() {
+            line.push(',');
+            line.push_str(&escape(key, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&escape(value, &[',', '=', ' ']));
+        }
+        let mut first = true;
+        for (key, value) in self.fields.iter() {
+            let value = match *value {
+                Value::Integer(i) => format!("{}i", i),
+                // Influx has no way to write these
+                Value::Float(f) if !f.is_finite() => continue,
+                Value::Float(f) => format!("{}", f),
+                Value::String(ref s) => format!("\"{}\"", escape(s, &['"', '\\'])),
+                Value::Boolean(b) => format!("{}", b),
+            };
+            line.push(if first { ' ' } else { ',' });
+            first = false;
+            line.push_str(&escape(key, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&value);
+        }
+        if first {
+            return None;
+        }
+        let (sec, nsec) = (self.timestamp.sec, self.timestamp.nsec as i64);
+        let timestamp = match precision {
+            Precision::Nanoseconds => sec * 1_000_000_000 + nsec,
+            Precision::Microseconds => sec * 1_000_000 + nsec / 1_000,
+            Precision::Milliseconds => sec * 1_000 + nsec / 1_000_000,
+            Precision::Seconds => sec,
+        };
+        line.push_str(&format!(" {}", timestamp));
+        Some(line)
+    }
+
+    /// A JSON object with `name`, `timestamp` (in seconds), `tags` and
+    /// `fields`
+    pub fn to_json(&self) -> String {
+        let mut tags = BTreeMap::new();
+        for (key, value) in self.tags.iter() {
+            tags.insert(key.clone(), Json::String(value.clone()));
+        }
+        let mut fields = BTreeMap::new();
+        for (key, value) in self.fields.iter() {
+            let value = match *value {
+                Value::Integer(i) => Json::I64(i),
+                Value::Float(f) => Json::F64(f),
+                Value::String(ref s) => Json::String(s.clone()),
+                Value::Boolean(b) => Json::Boolean(b),
+            };
+            fields.insert(key.clone(), value);
+        }
+        let mut object = BTreeMap::new();
+        object.insert("name".to_string(), Json::String(self.name.clone()));
+        object.insert("timestamp".to_string(), Json::I64(self.timestamp.sec));
+        object.insert("tags".to_string(), Json::Object(tags));
+        object.insert("fields".to_string(), Json::Object(fields));
+        Json::Object(object).to_string()
+    }
+
+    /// What the `forward` output sends an aggregator: the JSON object plus
+    /// which fields are counters and the nanoseconds of the timestamp
+    pub fn to_forward_json(&self) -> String {
+        let mut object = match Json::from_str(&self.to_json()) {
+            Ok(Json::Object(object)) => object,
+            _ => BTreeMap::new(),
+        };
+        object.insert("nsec".to_string(), Json::I64(self.timestamp.nsec as i64));
+        object.insert("counters".to_string(),
+                      Json::Array(self.counters.iter().map(|c| Json::String(c.clone())).collect()));
+        Json::Object(object).to_string()
+    }
+
+    /// Read back a line written by `to_forward_json`
+    pub fn from_forward_json(line: &str) -> Result<Metric, String> {
+        let json = try!(Json::from_str(line).map_err(|e| e.to_string()));
+        let name = try!(json.find("name").and_then(|n| n.as_string()).ok_or("no name".to_string()));
+        let sec = try!(json.find("timestamp").and_then(|t| t.as_i64()).ok_or("no timestamp".to_string()));
+        let nsec = json.find("nsec").and_then(|n| n.as_i64()).unwrap_or(0);
+        let mut metric = Metric::new(name);
+        metric.timestamp = Timespec::new(sec, nsec as i32);
+        if let Some(tags) = json.find("tags").and_then(|t| t.as_object()) {
+            for (key, value) in tags.iter() {
+                if let Some(value) = value.as_string() {
+                    metric.add_tag(key, value);
+                }
+            }
+        }
+        if let Some(fields) = json.find("fields").and_then(|f| f.as_object()) {
+            for (key, value) in fields.iter() {
+                let value = match *value {
+                    Json::I64(i) => Value::Integer(i),
+                    Json::U64(u) => Value::Integer(u as i64),
+                    Json::F64(f) => Value::Float(f),
+                    Json::String(ref s) => Value::String(s.clone()),
+                    Json::Boolean(b) => Value::Boolean(b),
+                    // NaN and infinity are written as null
+                    _ => continue,
+                };
+                metric.add_field(key, value);
+            }
+        }
+        if let Some(counters) = json.find("counters").and_then(|c| c.as_array()) {
+            for counter in counters.iter().filter_map(|c| c.as_string()) {
+                metric.counters.insert(counter.to_string());
+            }
+        }
+        Ok(metric)
+    }
+}
+
+// Backslash escape every character in `special`
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
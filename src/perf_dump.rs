@@ -0,0 +1,155 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use serde_json;
+
+use admin_socket;
+use metric::{Metric, Value};
+
+// Bits of a counter's `type` in `perf schema`, either means it only goes
+// up until the daemon restarts
+const PERFCOUNTER_LONGRUNAVG: u64 = 4;
+const PERFCOUNTER_COUNTER: u64 = 8;
+
+#[cfg(test)]
+mod tests {
+    use metric::Value;
+
+    #[test]
+    fn test_to_metric() {
+        let schema = r#"{
+            "paxos": {"commit": {"type": 10}, "commit_latency": {"type": 5}},
+            "throttle-mon_client_bytes": {"val": {"type": 2}, "get": {"type": 10}}
+        }"#;
+        let dump = r#"{
+            "paxos": {"commit": 45, "commit_latency": {"avgcount": 4, "sum": 0.5}},
+            "throttle-mon_client_bytes": {"val": 55, "get": 82},
+            "mon": {}
+        }"#;
+        let counters = super::counters(schema).unwrap();
+        assert_eq!(counters.iter().collect::<Vec<_>>(),
+                   vec!["paxos_commit", "paxos_commit_latency", "throttle_mon_client_bytes_get"]);
+
+        let metric = super::to_metric("mon_daemon", dump, &counters).unwrap();
+        assert_eq!(metric.fields.keys().collect::<Vec<_>>(),
+                   vec!["paxos_commit", "paxos_commit_latency", "paxos_commit_latency_avg", "paxos_commit_latency_count",
+                        "throttle_mon_client_bytes_get", "throttle_mon_client_bytes_val"]);
+        assert_eq!(metric.fields["paxos_commit"], Value::Integer(45));
+        assert_eq!(metric.fields["paxos_commit_latency"], Value::Float(0.5));
+        assert_eq!(metric.fields["paxos_commit_latency_count"], Value::Integer(4));
+        assert_eq!(metric.fields["paxos_commit_latency_avg"], Value::Float(0.125));
+        assert!(metric.is_counter("paxos_commit_latency_count"));
+        assert!(!metric.is_counter("paxos_commit_latency_avg"));
+        assert!(metric.is_counter("throttle_mon_client_bytes_get"));
+        assert!(!metric.is_counter("throttle_mon_client_bytes_val"));
+    }
+}
+
+/// A measurement with a field for every counter in a `perf dump`, named
+/// for its section and counter, ie: `paxos_commit`.  A long run average is
+/// sent as its sum, its `_count` and the `_avg` of the two.  Only the
+/// fields `counters` names are marked as counters.
+pub fn to_metric(name: &str, dump: &str, counters: &BTreeSet<String>) -> Result<Metric, String> {
+    let dump: serde_json::Value = try!(serde_json::from_str(dump).map_err(|e| e.to_string()));
+    let mut metric = Metric::new(name);
+    add_fields(&mut metric, &dump, "", counters);
+    Ok(metric)
+}
+
+fn add_fields(metric: &mut Metric, node: &serde_json::Value, path: &str, counters: &BTreeSet<String>) {
+    if let Some(object) = node.as_object() {
+        let sum = object.get("sum").and_then(|s| s.as_f64());
+        let count = object.get("avgcount").and_then(|c| c.as_u64());
+        if let (Some(sum), Some(count)) = (sum, count) {
+            metric.add_counter(path, Value::Float(sum));
+            metric.add_counter(&format!("{}_count", path), Value::Integer(count as i64));
+            let avg = if count > 0 { sum / count as f64 } else { 0.0 };
+            metric.add_field(&format!("{}_avg", path), Value::Float(avg));
+            return;
+        }
+        for (key, child) in object.iter() {
+            add_fields(metric, child, &join(path, key), counters);
+        }
+        return;
+    }
+    // Histograms and anything else that isn't a number are left out
+    let value = if let Some(n) = node.as_u64() {
+        Value::Integer(n as i64)
+    } else if let Some(n) = node.as_i64() {
+        Value::Integer(n)
+    } else if let Some(f) = node.as_f64() {
+        Value::Float(f)
+    } else {
+        return;
+    };
+    if counters.contains(path) {
+        metric.add_counter(path, value);
+    } else {
+        metric.add_field(path, value);
+    }
+}
+
+/// The field names of the counters `perf schema` describes as only going up
+pub fn counters(schema: &str) -> Result<BTreeSet<String>, String> {
+    let schema: serde_json::Value = try!(serde_json::from_str(schema).map_err(|e| e.to_string()));
+    let mut counters = BTreeSet::new();
+    add_counters(&mut counters, &schema, "");
+    Ok(counters)
+}
+
+fn add_counters(counters: &mut BTreeSet<String>, node: &serde_json::Value, path: &str) {
+    if let Some(object) = node.as_object() {
+        match object.get("type").and_then(|t| t.as_u64()) {
+            Some(kind) => {
+                if kind & (PERFCOUNTER_LONGRUNAVG | PERFCOUNTER_COUNTER) != 0 {
+                    counters.insert(path.to_string());
+                }
+            },
+            None => {
+                for (key, child) in object.iter() {
+                    add_counters(counters, child, &join(path, key));
+                }
+            },
+        }
+    }
+}
+
+// Section names such as `throttle-mon_client_bytes` aren't valid field
+// names everywhere, so anything but letters, digits and `_` becomes `_`
+fn join(path: &str, key: &str) -> String {
+    let key: String = key.chars().map(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => c,
+        _ => '_',
+    }).collect();
+    if path.is_empty() {
+        key
+    } else {
+        format!("{}_{}", path, key)
+    }
+}
+
+/// Each daemon's counters from its `perf schema`, which only changes when
+/// the daemon is upgraded, so it's asked once until it stops answering
+pub struct Schemas {
+    counters: HashMap<String, BTreeSet<String>>,
+}
+
+impl Schemas {
+    pub fn new() -> Schemas {
+        Schemas { counters: HashMap::new() }
+    }
+
+    pub fn counters(&mut self, socket: &str) -> Result<&BTreeSet<String>, String> {
+        if !self.counters.contains_key(socket) {
+            let schema = try!(admin_socket::perf_schema(Path::new(socket)));
+            let counters = try!(counters(&schema));
+            self.counters.insert(socket.to_string(), counters);
+        }
+        Ok(&self.counters[socket])
+    }
+
+    /// Ask again next time, the daemon may come back upgraded
+    pub fn forget(&mut self, socket: &str) {
+        self.counters.remove(socket);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
+use metric::{Metric, Value};
+
+#[cfg(test)]
+mod tests {
+    use metric::{Metric, Value};
+
+    fn sample(sec: i64, ops: i64, latency: f64, latency_count: i64) -> Metric {
+        let mut metric = Metric::new("osd_daemon");
+        metric.timestamp.sec = sec;
+        metric.timestamp.nsec = 0;
+        metric.add_field("stat_bytes_used", Value::Integer(1024));
+        metric.add_counter("ops", Value::Integer(ops));
+        metric.add_counter("op_latency", Value::Float(latency));
+        metric.add_counter("op_latency_count", Value::Integer(latency_count));
+        metric
+    }
+
+    #[test]
+    fn test_rates() {
+        let mut rates = super::Rates::new();
+        let mut first = sample(1458000000, 100, 10.0, 100);
+        rates.add("osd.3", &mut first);
+        assert_eq!(first.fields.len(), 4);
+
+        let mut second = sample(1458000005, 150, 12.0, 120);
+        rates.add("osd.3", &mut second);
+        assert_eq!(second.fields["ops_rate"], Value::Float(10.0));
+        assert_eq!(second.fields["op_latency_count_rate"], Value::Float(4.0));
+        // 2 seconds over 20 operations
+        assert_eq!(second.fields["op_latency_interval_avg"], Value::Float(0.1));
+        assert!(!second.fields.contains_key("op_latency_rate"));
+        assert!(!second.fields.contains_key("stat_bytes_used_rate"));
+        assert!(!second.is_counter("ops_rate"));
+
+        // Restarted, so there's nothing to compare with
+        let mut restarted = sample(1458000010, 5, 0.1, 5);
+        rates.add("osd.3", &mut restarted);
+        assert_eq!(restarted.fields.len(), 4);
+        // No operations in the interval, no average
+        let mut idle = sample(1458000015, 55, 0.1, 5);
+        rates.add("osd.3", &mut idle);
+        assert_eq!(idle.fields["ops_rate"], Value::Float(10.0));
+        assert!(!idle.fields.contains_key("op_latency_interval_avg"));
+
+        // Gone away, and back with counters that may be higher by then
+        rates.forget("osd.3");
+        let mut back = sample(1458000600, 70, 0.2, 10);
+        rates.add("osd.3", &mut back);
+        assert_eq!(back.fields.len(), 4);
+    }
+}
+
+/// The previous sample of each daemon, to send what its counters did in
+/// between as well as their totals.  A counter with a `_count` alongside is
+/// a latency sum, which gets the `_interval_avg` of the operations in
+/// between; the others get a per second `_rate`.
+pub struct Rates {
+    previous: HashMap<String, Metric>,
+}
+
+impl Rates {
+    pub fn new() -> Rates {
+        Rates { previous: HashMap::new() }
+    }
+
+    /// Add the rates since `daemon`'s last sample to `metric`
+    pub fn add(&mut self, daemon: &str, metric: &mut Metric) {
+        let previous = match self.previous.insert(daemon.to_string(), metric.clone()) {
+            Some(previous) => previous,
+            None => return,
+        };
+        let secs = (metric.timestamp.sec - previous.timestamp.sec) as f64 +
+                   (metric.timestamp.nsec - previous.timestamp.nsec) as f64 / 1e9;
+        if secs <= 0.0 {
+            return;
+        }
+        let mut deltas = BTreeMap::new();
+        for key in metric.counters.iter() {
+            let (now, then) = match (number(metric.fields.get(key)), number(previous.fields.get(key))) {
+                (Some(now), Some(then)) => (now, then),
+                _ => continue,
+            };
+            if now < then {
+                // Every counter starts again from zero when the daemon restarts
+                debug!("Counters of {} went backwards, taking it as a restart", daemon);
+                return;
+            }
+            deltas.insert(key.clone(), now - then);
+        }
+        let mut fields = Vec::new();
+        for (key, delta) in deltas.iter() {
+            match deltas.get(&format!("{}_count", key)) {
+                Some(&count) => {
+                    if count > 0.0 {
+                        fields.push((format!("{}_interval_avg", key), delta / count));
+                    }
+                },
+                None => fields.push((format!("{}_rate", key), delta / secs)),
+            }
+        }
+        for (key, value) in fields.into_iter() {
+            metric.add_field(&key, Value::Float(value));
+        }
+    }
+
+    /// Start again from the next sample, the daemon may have restarted in
+    /// between without its counters having gone below the last ones
+    pub fn forget(&mut self, daemon: &str) {
+        self.previous.remove(daemon);
+    }
+}
+
+fn number(value: Option<&Value>) -> Option<f64> {
+    match value {
+        Some(&Value::Integer(i)) => Some(i as f64),
+        Some(&Value::Float(f)) => Some(f),
+        _ => None,
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use libc;
+
+use config::{self, Args, ConfigError, SharedArgs, Sources};
+
+static RELOAD_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevel;
+
+    use config::{self, Mode, SharedArgs};
+
+    fn args(yaml: &str) -> config::Args {
+        config::parse(yaml, "test.yaml", &[], LogLevel::Info).unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let shared = SharedArgs::new(args("outputs: [stdout]"));
+        assert_eq!(shared.generation(), 0);
+
+        super::apply(&shared, Ok(args("outputs: [stdout, influx]")));
+        assert_eq!(shared.generation(), 1);
+        assert_eq!(shared.current().outputs, vec!["stdout", "influx"]);
+
+        // A broken file leaves everything as it was
+        let broken = config::parse("outputs: []", "test.yaml", &[], LogLevel::Info);
+        super::apply(&shared, broken);
+        assert_eq!(shared.generation(), 1);
+        assert_eq!(shared.current().outputs, vec!["stdout", "influx"]);
+
+        // The mode only changes on a restart
+        super::apply(&shared, Ok(args("mode: aggregator\noutputs: [stdout]")));
+        assert_eq!(shared.generation(), 2);
+        assert_eq!(shared.current().outputs, vec!["stdout"]);
+        assert_eq!(shared.current().mode, Mode::Agent);
+    }
+}
+
+// Only async-signal-safe work is allowed in here, so the handler just flags
+// the reload for the main thread to pick up
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn install_sighup_handler() {
+    unsafe {
+        libc::signal(libc::SIGHUP, request_reload as libc::sighandler_t);
+    }
+}
+
+/// Re-read the config file if a SIGHUP arrived since the last call, applying
+/// the same overrides as at startup.  An invalid file is logged and the
+/// running configuration is kept.
+pub fn reload_if_requested(shared: &Arc<SharedArgs>, sources: &Sources) {
+    if !RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    info!("SIGHUP received, reloading {}", sources.config_path);
+    apply(shared, config::load(sources));
+}
+
+// Swap in a freshly loaded configuration, keeping the mode the process was
+// started in
+fn apply(shared: &Arc<SharedArgs>, loaded: Result<Args, ConfigError>) {
+    match loaded {
+        Ok(mut args) => {
+            let mode = shared.current().mode;
+            if args.mode != mode {
+                warn!("mode changed to {:?}, still running as {:?} until restarted", args.mode, mode);
+                args.mode = mode;
+            }
+            info!("Reloaded configuration: {:?}", args);
+            shared.replace(args);
+        },
+        Err(e) => {
+            error!("Keeping the current configuration, reload failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread;
+use std::time::Duration;
+
+use time;
+
+// Don't hold up the other sinks trying to reach a server that's down
+const RECONNECT_SECS: i64 = 10;
+const WRITE_TIMEOUT_SECS: u64 = 10;
+
+// Writes waiting for the writer thread.  Any more and the server is too
+// slow or can't be reached, so new writes are dropped.
+const QUEUE_WRITES: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut connection = super::Connection::with_preamble("127.0.0.1", port, b"AUTH s3cret\n".to_vec());
+        connection.send(b"a 1\n").unwrap();
+        connection.send(b"b 2\n").unwrap();
+        // Written by the time it's dropped
+        drop(connection);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "AUTH s3cret\na 1\nb 2\n");
+    }
+
+    #[test]
+    fn test_send_behind() {
+        // Connected, but nothing is ever read, so the writer gets stuck once
+        // the socket buffers are full
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut connection = super::Connection::new("127.0.0.1", listener.local_addr().unwrap().port());
+        let chunk = vec![b'x'; 1024 * 1024];
+        let dropped = (0..256).map(|_| connection.send(&chunk)).any(|result| result.is_err());
+        assert!(dropped);
+    }
+}
+
+/// A TCP connection for line based protocols.  Connecting and writing
+/// happen on a thread of their own, so that a server that's slow or drops
+/// packets holds up that rather than the pipeline; it reconnects on the
+/// next write after a failure, at most every `RECONNECT_SECS`.
+pub struct Connection {
+    host: String,
+    port: u16,
+    // Written first on every new connection
+    preamble: Vec<u8>,
+    // Started with the first write, some sinks only need it for one of
+    // their protocols
+    writes: Option<SyncSender<Vec<u8>>>,
+}
+
+impl Connection {
+    pub fn new(host: &str, port: u16) -> Connection {
+        Connection::with_preamble(host, port, Vec::new())
+    }
+
+    /// A connection that starts with `preamble` each time it's made, such
+    /// as a line to authenticate with
+    pub fn with_preamble(host: &str, port: u16, preamble: Vec<u8>) -> Connection {
+        Connection {
+            host: host.to_string(),
+            port: port,
+            preamble: preamble,
+            writes: None,
+        }
+    }
+
+    /// Queue `data` to be sent, or drop it if the writer is too far behind.
+    /// Anything queued is still sent after the connection is dropped.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.writes.is_none() {
+            let (writes, queue) = sync_channel(QUEUE_WRITES);
+            let writer = Writer {
+                host: self.host.clone(),
+                port: self.port,
+                preamble: self.preamble.clone(),
+                stream: None,
+                last_connect: 0,
+            };
+            thread::spawn(move || writer.run(queue));
+            self.writes = Some(writes);
+        }
+        let result = match self.writes {
+            Some(ref writes) => writes.try_send(data.to_vec()),
+            None => return Ok(()),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(format!("{}:{}: too far behind", self.host, self.port)),
+            Err(TrySendError::Disconnected(_)) => {
+                // Start another writer next time
+                self.writes = None;
+                Err(format!("{}:{}: the writer has gone", self.host, self.port))
+            },
+        }
+    }
+}
+
+// Owns the TCP connection
+struct Writer {
+    host: String,
+    port: u16,
+    preamble: Vec<u8>,
+    stream: Option<TcpStream>,
+    // When it last tried to connect, or lost the connection
+    last_connect: i64,
+}
+
+impl Writer {
+    // Until the connection has gone and everything it queued has been sent
+    fn run(mut self, queue: Receiver<Vec<u8>>) {
+        for data in queue.iter() {
+            if let Err(e) = self.write(&data) {
+                debug!("Unable to send {} bytes to {}:{}: {}", data.len(), self.host, self.port, e);
+            }
+        }
+    }
+
+    // Send `data`, or drop it if the server can't be reached
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        let result = match self.connect() {
+            Some(stream) => stream.write_all(data).map_err(|e| e.to_string()),
+            None => return Err("not connected".to_string()),
+        };
+        if result.is_err() {
+            // Wait before trying again rather than on the next write
+            self.stream = None;
+            self.last_connect = time::get_time().sec;
+        }
+        result
+    }
+
+    fn connect(&mut self) -> Option<&mut TcpStream> {
+        if self.stream.is_none() {
+            let now = time::get_time().sec;
+            if now - self.last_connect < RECONNECT_SECS {
+                return None;
+            }
+            self.last_connect = now;
+            match TcpStream::connect((&self.host[..], self.port)) {
+                Ok(mut stream) => {
+                    let _ = stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)));
+                    match stream.write_all(&self.preamble) {
+                        Ok(()) => {
+                            debug!("Connected to {}:{}", self.host, self.port);
+                            self.stream = Some(stream);
+                        },
+                        Err(e) => debug!("Unable to start talking to {}:{}: {}", self.host, self.port, e),
+                    }
+                },
+                Err(e) => {
+                    debug!("Unable to connect to {}:{}: {}", self.host, self.port, e);
+                }
+            }
+        }
+        self.stream.as_mut()
+    }
+}
@@ -0,0 +1,318 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use time;
+
+use config::Archive;
+use metric::Metric;
+use sinks::Sink;
+
+// The file being written, closed files are renamed to
+// `metrics-<time closed>.jsonl` and sort in the order they were written
+const CURRENT_FILE: &'static str = "metrics.jsonl";
+const CLOSED_PREFIX: &'static str = "metrics-";
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    use flate2::read::GzDecoder;
+
+    use config::Archive;
+    use metric::{Metric, Value};
+    use sinks::Sink;
+    use sinks::mock::scratch_dir;
+
+    #[test]
+    fn test_rotation() {
+        let dir = scratch_dir("archive");
+        let archive = Archive {
+            path: dir.to_string_lossy().into_owned(),
+            max_size_mb: 1,
+            rotate_secs: 3600,
+            gzip: false,
+            keep: 2,
+        };
+        let mut sink = super::FileSink::new(&archive);
+        sink.max_bytes = 150;
+        let mut metric = Metric::new("mon_daemon");
+        metric.add_tag("hostname", "mon-1");
+        metric.add_field("osds", Value::Integer(3));
+        // Two lines don't fit, so each write after the first rotates
+        for _ in 0..3 {
+            sink.write(&metric);
+        }
+        sink.wait();
+        assert_eq!(super::closed_files(&dir).unwrap().len(), 2);
+        // Old enough to be rotated whatever its size
+        sink.max_bytes = 1024 * 1024;
+        sink.opened -= 3600;
+        sink.write(&metric);
+        sink.wait();
+
+        let closed = super::closed_files(&dir).unwrap();
+        assert_eq!(closed.len(), 2);
+        let mut current = String::new();
+        File::open(dir.join(super::CURRENT_FILE)).unwrap().read_to_string(&mut current).unwrap();
+        assert_eq!(current, metric.to_json().to_string() + "\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_closed_path() {
+        let dir = scratch_dir("closed_path");
+        let archive = Archive {
+            path: dir.to_string_lossy().into_owned(),
+            max_size_mb: 1,
+            rotate_secs: 3600,
+            gzip: false,
+            keep: 100,
+        };
+        let sink = super::FileSink::new(&archive);
+        // More than fit in a second without a suffix and a single digit
+        let mut created = Vec::new();
+        for _ in 0..12 {
+            let path = sink.closed_path().unwrap();
+            File::create(&path).unwrap();
+            created.push(path);
+        }
+        assert_eq!(super::closed_files(&dir).unwrap(), created);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compress() {
+        let dir = scratch_dir("compress");
+        let path = dir.join("metrics-20160315T000000Z.jsonl");
+        File::create(&path).unwrap().write_all(b"{\"name\":\"mon_daemon\"}\n").unwrap();
+        super::compress(&path).unwrap();
+        assert!(!path.exists());
+
+        let mut decoder = GzDecoder::new(File::open(dir.join("metrics-20160315T000000Z.jsonl.gz")).unwrap()).unwrap();
+        let mut text = String::new();
+        decoder.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "{\"name\":\"mon_daemon\"}\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_clean_up() {
+        let dir = scratch_dir("clean_up");
+        // Interrupted while compressing, and after compressing but before
+        // removing the original
+        for name in ["metrics-20160315T000000Z.jsonl", "metrics-20160315T000000Z.jsonl.gz.tmp",
+                     "metrics-20160316T000000Z.jsonl", "metrics-20160316T000000Z.jsonl.gz"].iter() {
+            File::create(dir.join(name)).unwrap();
+        }
+        super::clean_up(&dir);
+        let closed = super::closed_files(&dir).unwrap();
+        assert_eq!(closed, vec![dir.join("metrics-20160315T000000Z.jsonl"), dir.join("metrics-20160316T000000Z.jsonl.gz")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+/// Appends every measurement to `metrics.jsonl` in the archive directory,
+/// which is kept around for post-mortems when nothing else was listening
+pub struct FileSink {
+    archive: Archive,
+    dir: PathBuf,
+    max_bytes: u64,
+    file: Option<File>,
+    size: u64,
+    opened: i64,
+    // Compresses and prunes closed files, one rotation at a time so that
+    // a prune never sees a file that's still being compressed
+    worker: Option<JoinHandle<()>>,
+}
+
+impl FileSink {
+    pub fn new(archive: &Archive) -> FileSink {
+        let dir = PathBuf::from(&archive.path);
+        // The sink from before a reload has finished with the directory by
+        // now, so anything half done was left by a crash
+        clean_up(&dir);
+        FileSink {
+            archive: archive.clone(),
+            dir: dir,
+            max_bytes: archive.max_size_mb * 1024 * 1024,
+            file: None,
+            size: 0,
+            opened: 0,
+            worker: None,
+        }
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            try!(fs::create_dir_all(&self.dir));
+            let file = try!(OpenOptions::new().append(true).create(true).open(self.dir.join(CURRENT_FILE)));
+            // Carry on with whatever the last run left behind
+            self.size = try!(file.metadata()).len();
+            self.opened = time::get_time().sec;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.wait();
+        let closed = try!(self.closed_path());
+        try!(fs::rename(self.dir.join(CURRENT_FILE), &closed));
+        debug!("Rotated the archive to {}", closed.display());
+        let dir = self.dir.clone();
+        let keep = self.archive.keep as usize;
+        let gzip = self.archive.gzip;
+        // Don't hold up the other sinks while it's compressed
+        self.worker = Some(thread::spawn(move || {
+            if gzip {
+                if let Err(e) = compress(&closed) {
+                    warn!("Unable to compress {}: {}", closed.display(), e);
+                }
+            }
+            prune(&dir, keep);
+        }));
+        Ok(())
+    }
+
+    // Until the last rotation has been compressed and pruned
+    fn wait(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                warn!("Compressing the archive in {} failed", self.dir.display());
+            }
+        }
+    }
+
+    // Named for when it was closed, with a suffix if that's taken
+    fn closed_path(&self) -> io::Result<PathBuf> {
+        let stamp = try!(time::strftime("%Y%m%dT%H%M%SZ", &time::now_utc())
+                             .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())));
+        let mut n = 0;
+        loop {
+            let name = if n == 0 {
+                format!("{}{}.jsonl", CLOSED_PREFIX, stamp)
+            } else {
+                // Padded so that the tenth sorts after the ninth
+                format!("{}{}_{:04}.jsonl", CLOSED_PREFIX, stamp, n)
+            };
+            let path = self.dir.join(name);
+            let gzipped = PathBuf::from(format!("{}.gz", path.display()));
+            if !path.exists() && !gzipped.exists() {
+                return Ok(path);
+            }
+            n += 1;
+        }
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        if self.file.is_some() {
+            let full = self.size > 0 && self.size + line.len() as u64 > self.max_bytes;
+            let old = time::get_time().sec - self.opened >= self.archive.rotate_secs as i64;
+            if full || old {
+                try!(self.rotate());
+            }
+        }
+        try!(try!(self.open()).write_all(line.as_bytes()));
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, metric: &Metric) {
+        let line = format!("{}\n", metric.to_json());
+        if let Err(e) = self.append(&line) {
+            // Try again from scratch with the next measurement
+            self.file = None;
+            debug!("Unable to archive {} in {}: {}", metric.name, self.archive.path, e);
+        }
+    }
+}
+
+// gzip `path` to `path.gz` and remove it
+fn compress(path: &Path) -> io::Result<()> {
+    let gzipped = PathBuf::from(format!("{}.gz", path.display()));
+    let partial = PathBuf::from(format!("{}.gz.tmp", path.display()));
+    let mut source = try!(File::open(path));
+    let mut encoder = GzEncoder::new(try!(File::create(&partial)), Compression::Default);
+    try!(io::copy(&mut source, &mut encoder));
+    try!(try!(encoder.finish()).sync_all());
+    try!(fs::rename(&partial, &gzipped));
+    fs::remove_file(path)
+}
+
+// Remove what a compression that was interrupted left behind: the partial
+// `.gz.tmp`, or the original once its `.gz` is complete
+fn clean_up(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let stale = name.starts_with(CLOSED_PREFIX) &&
+                    (name.ends_with(".gz.tmp") || (name.ends_with(".jsonl") && dir.join(format!("{}.gz", name)).exists()));
+        if stale {
+            match fs::remove_file(&path) {
+                Ok(_) => debug!("Removed {}, left over from compressing the archive", path.display()),
+                Err(e) => warn!("Unable to remove {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+// Closed files, oldest first
+fn closed_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let closed = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.starts_with(CLOSED_PREFIX) && (name.ends_with(".jsonl") || name.ends_with(".jsonl.gz")),
+            None => false,
+        };
+        if closed {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Remove all but the newest `keep` closed files
+fn prune(dir: &Path, keep: usize) {
+    let files = match closed_files(dir) {
+        Ok(files) => files,
+        Err(e) => {
+            warn!("Unable to list the archive in {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    if files.len() <= keep {
+        return;
+    }
+    for path in files[..files.len() - keep].iter() {
+        match fs::remove_file(path) {
+            Ok(_) => debug!("Removed {} from the archive", path.display()),
+            Err(e) => warn!("Unable to remove {}: {}", path.display(), e),
+        }
+    }
+}
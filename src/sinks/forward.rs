@@ -0,0 +1,110 @@
+use std::mem;
+
+use config::Forward;
+use metric::Metric;
+use sinks::Sink;
+use sinks::connection::Connection;
+
+// Send once this much is waiting even if more measurements are coming
+const MAX_BUFFER_BYTES: usize = 65536;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    use log::LogLevel;
+
+    use config::{self, Forward};
+    use metric::{Metric, Value};
+    use sinks::Sink;
+
+    #[test]
+    fn test_forward() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut forward = Forward::default();
+        forward.port = listener.local_addr().unwrap().port();
+        let mut sink = super::ForwardSink::new(&forward);
+
+        let mut metric = Metric::new("osd_daemon");
+        metric.add_tag("osd_num", "3");
+        metric.add_counter("ops", Value::Integer(4889));
+        sink.write(&metric);
+        sink.write(&metric);
+        sink.flush();
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        for _ in 0..2 {
+            let received = Metric::from_forward_json(&lines.next().unwrap().unwrap()).unwrap();
+            assert_eq!(received.tags, metric.tags);
+            assert!(received.is_counter("ops"));
+        }
+    }
+
+    #[test]
+    fn test_forward_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let file = format!("outputs: [forward]\nforward: {{port: {}, token: s3cret}}",
+                           listener.local_addr().unwrap().port());
+        let args = config::parse(&file, "test.yaml", &[], LogLevel::Info).unwrap();
+        let mut sink = super::ForwardSink::new(&args.forward);
+        sink.write(&Metric::new("osd_daemon"));
+        sink.flush();
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "AUTH s3cret");
+        assert!(Metric::from_forward_json(&lines.next().unwrap().unwrap()).is_ok());
+    }
+}
+
+/// Sends every measurement on to an aggregator over TCP, as a JSON object
+/// per line after an `AUTH` line with the token if there is one
+pub struct ForwardSink {
+    connection: Connection,
+    buffer: String,
+}
+
+impl ForwardSink {
+    pub fn new(forward: &Forward) -> ForwardSink {
+        let preamble = match forward.token {
+            Some(ref token) => format!("AUTH {}\n", token.expose()).into_bytes(),
+            None => Vec::new(),
+        };
+        ForwardSink {
+            connection: Connection::with_preamble(&forward.host, forward.port, preamble),
+            buffer: String::new(),
+        }
+    }
+
+    fn send(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let buffer = mem::replace(&mut self.buffer, String::new());
+        if let Err(e) = self.connection.send(buffer.as_bytes()) {
+            debug!("Unable to forward {} bytes of measurements: {}", buffer.len(), e);
+        }
+    }
+}
+
+impl Sink for ForwardSink {
+    fn write(&mut self, metric: &Metric) {
+        self.buffer.push_str(&metric.to_forward_json());
+        self.buffer.push('\n');
+        if self.buffer.len() >= MAX_BUFFER_BYTES {
+            self.send();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+}
+
+impl Drop for ForwardSink {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
@@ -0,0 +1,182 @@
+use std::mem;
+
+use config::{Graphite, GraphiteProtocol};
+use metric::{Metric, Value};
+use sinks::Sink;
+use sinks::connection::Connection;
+
+#[cfg(test)]
+mod tests {
+    use metric::{Metric, Value};
+
+    #[test]
+    fn test_metric_path() {
+        let mut metric = Metric::new("osd_daemon");
+        metric.add_tag("hostname", "ceph-1.example.com");
+        metric.add_tag("osd_num", "3");
+        metric.add_tag("drive_name", "");
+        assert_eq!(super::metric_path("ceph.{hostname}.osd.{osd_num}", &metric), "ceph.ceph-1_example_com.osd.3");
+        assert_eq!(super::metric_path("{measurement}.{drive_name}.{rack}", &metric), "osd_daemon.unknown.unknown");
+    }
+
+    #[test]
+    fn test_pickle() {
+        let mut metric = Metric::new("mon_daemon");
+        metric.add_tag("hostname", "mon-1");
+        metric.timestamp.sec = 1458000000;
+        metric.add_field("osds", Value::Integer(3));
+        let points = super::points("ceph.{hostname}", &metric);
+        assert_eq!(super::pickle(&points, metric.timestamp.sec),
+                   vec![0, 0, 0, 42,
+                        0x80, 2, b']', b'(',
+                        b'X', 15, 0, 0, 0, b'c', b'e', b'p', b'h', b'.', b'm', b'o', b'n', b'-', b'1', b'.', b'o', b's', b'd', b's',
+                        b'J', 0x80, 0x50, 0xe7, 0x56,
+                        b'G', 0x40, 0x08, 0, 0, 0, 0, 0, 0,
+                        0x86, 0x86,
+                        b'e', b'.']);
+    }
+}
+
+pub struct GraphiteSink {
+    graphite: Graphite,
+    connection: Connection,
+}
+
+impl GraphiteSink {
+    pub fn new(graphite: &Graphite) -> GraphiteSink {
+        GraphiteSink {
+            graphite: graphite.clone(),
+            connection: Connection::new(&graphite.host, graphite.port),
+        }
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn write(&mut self, metric: &Metric) {
+        let points = points(self.graphite.template(&metric.name), metric);
+        if points.is_empty() {
+            return;
+        }
+        let message = match self.graphite.protocol {
+            GraphiteProtocol::Plaintext => {
+                let mut lines = String::new();
+                for &(ref path, ref value) in points.iter() {
+                    lines.push_str(&format!("{} {} {}\n", path, value, metric.timestamp.sec));
+                }
+                lines.into_bytes()
+            },
+            GraphiteProtocol::Pickle => pickle(&points, metric.timestamp.sec),
+        };
+        if let Err(e) = self.connection.send(&message) {
+            debug!("Unable to send {} to Carbon: {}", metric.name, e);
+        }
+    }
+}
+
+// Every numeric field of the metric below its templated path
+fn points(template: &str, metric: &Metric) -> Vec<(String, Point)> {
+    let base = metric_path(template, metric);
+    let mut points = Vec::new();
+    for (key, value) in metric.fields.iter() {
+        let point = match *value {
+            Value::Integer(i) => Point::Integer(i),
+            Value::Float(f) if f.is_finite() => Point::Float(f),
+            Value::Boolean(b) => Point::Integer(if b { 1 } else { 0 }),
+            _ => continue,
+        };
+        points.push((format!("{}.{}", base, component(key)), point));
+    }
+    points
+}
+
+enum Point {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Point {
+    fn as_f64(&self) -> f64 {
+        match *self {
+            Point::Integer(i) => i as f64,
+            Point::Float(f) => f,
+        }
+    }
+}
+
+impl ::std::fmt::Display for Point {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Point::Integer(i) => write!(f, "{}", i),
+            Point::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+// Fill in `{tag}` and `{measurement}`.  Templates are checked for balanced
+// braces when the config is loaded.
+pub fn metric_path(template: &str, metric: &Metric) -> String {
+    let mut path = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        path.push_str(&rest[..start]);
+        let end = rest[start..].find('}').map_or(rest.len(), |e| start + e);
+        let name = &rest[start + 1..end];
+        let value = if name == "measurement" {
+            Some(&metric.name)
+        } else {
+            metric.tags.get(name)
+        };
+        path.push_str(&component(value.map_or("", |v| &v[..])));
+        rest = if end < rest.len() { &rest[end + 1..] } else { "" };
+    }
+    path.push_str(rest);
+    path
+}
+
+// A single path component: dots would add levels to the hierarchy
+pub fn component(value: &str) -> String {
+    if value.is_empty() {
+        return "unknown".to_string();
+    }
+    value.chars().map(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => c,
+        _ => '_',
+    }).collect()
+}
+
+// A pickle protocol 2 list of (path, (timestamp, value)) tuples, prefixed
+// with its length as Carbon's pickle receiver expects
+fn pickle(points: &[(String, Point)], timestamp: i64) -> Vec<u8> {
+    // PROTO 2, EMPTY_LIST, MARK
+    let mut payload = vec![0x80, 2, b']', b'('];
+    for &(ref path, ref point) in points.iter() {
+        // BINUNICODE
+        payload.push(b'X');
+        payload.extend_from_slice(&u32_le(path.len() as u32));
+        payload.extend_from_slice(path.as_bytes());
+        // BININT, fine until 2038
+        payload.push(b'J');
+        payload.extend_from_slice(&u32_le(timestamp as u32));
+        // BINFLOAT
+        payload.push(b'G');
+        let bits: u64 = unsafe { mem::transmute(point.as_f64()) };
+        for i in 0..8 {
+            payload.push((bits >> (56 - i * 8)) as u8);
+        }
+        // TUPLE2 (timestamp, value) then TUPLE2 (path, ...)
+        payload.push(0x86);
+        payload.push(0x86);
+    }
+    // APPENDS, STOP
+    payload.push(b'e');
+    payload.push(b'.');
+
+    let len = payload.len() as u32;
+    let mut message = vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    message.extend_from_slice(&payload);
+    message
+}
+
+fn u32_le(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
@@ -0,0 +1,245 @@
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{self, Client};
+use hyper::header::Headers;
+use hyper::net::{self, HttpConnector, HttpStream, HttpsConnector, NetworkConnector};
+use hyper::status::StatusCode;
+use openssl::nid::Nid;
+use openssl::ssl::{SSL_VERIFY_PEER, Ssl, SslContext, SslStream};
+use openssl::x509::{X509, X509StoreContext};
+
+// How long a request may go without any progress before it's given up on,
+// so a backend that accepts connections but never answers can't hang a
+// sink
+const TIMEOUT_SECS: u64 = 10;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use hyper::header::Headers;
+    use openssl::ssl::{SslContext, SslMethod, SslStream};
+    use openssl::x509::{X509, X509FileType};
+
+    use sinks::mock;
+
+    macro_rules! testdata {
+        ($name:expr) => (concat!(env!("CARGO_MANIFEST_DIR"), "/src/sinks/testdata/", $name))
+    }
+
+    // Answers one request over TLS with a 204, presenting `cert`
+    fn tls_server(cert: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut context = SslContext::new(SslMethod::Sslv23).unwrap();
+            context.set_certificate_file(cert, X509FileType::PEM).unwrap();
+            context.set_private_key_file(testdata!("server.key"), X509FileType::PEM).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            // The client hangs up on a certificate it doesn't like
+            let stream = match SslStream::accept(&context, stream) {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            let mut reader = BufReader::new(stream);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+            }
+            reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").unwrap();
+        });
+        port
+    }
+
+    fn client(host: &str) -> ::hyper::Client {
+        let mut context = SslContext::new(SslMethod::Sslv23).unwrap();
+        context.set_CA_file(testdata!("ca.pem")).unwrap();
+        super::https_client(context, host)
+    }
+
+    #[test]
+    fn test_https_host() {
+        let port = tls_server(testdata!("localhost.pem"));
+        let res = client("localhost").get(&format!("https://localhost:{}/", port)[..]).send().unwrap();
+        assert_eq!(res.status.to_u16(), 204);
+
+        // Signed by the right CA, but for some other server
+        let port = tls_server(testdata!("wrong_host.pem"));
+        assert!(client("localhost").get(&format!("https://localhost:{}/", port)[..]).send().is_err());
+    }
+
+    #[test]
+    fn test_post() {
+        let (port, requests) = mock::http_server(1);
+        let url = format!("http://127.0.0.1:{}/write", port);
+        let (status, text) = super::post(&super::client(), &url, Headers::new(), "text/plain", b"ops=1").unwrap();
+        assert_eq!(status.to_u16(), 204);
+        assert_eq!(text, "");
+        assert_eq!(requests.recv().unwrap(), ("POST /write HTTP/1.1".to_string(), "ops=1".to_string()));
+    }
+
+    #[test]
+    fn test_names_host() {
+        let localhost = X509::from_pem(&mut &include_bytes!("testdata/localhost.pem")[..]).unwrap();
+        assert!(super::names_host(&localhost, "localhost"));
+        assert!(super::names_host(&localhost, "LOCALHOST"));
+        assert!(super::names_host(&localhost, "127.0.0.1"));
+        assert!(!super::names_host(&localhost, "127.0.0.2"));
+        assert!(!super::names_host(&localhost, "influx.example.com"));
+
+        // The common name only counts when there are no alternative names
+        let cn_only = X509::from_pem(&mut &include_bytes!("testdata/cn_only.pem")[..]).unwrap();
+        assert!(super::names_host(&cn_only, "cn-only.example.com"));
+        assert!(!super::names_host(&cn_only, "example.com"));
+
+        assert!(super::matches_dns("*.example.com", "influx.example.com"));
+        assert!(!super::matches_dns("*.example.com", "example.com"));
+        assert!(!super::matches_dns("*.example.com", "a.influx.example.com"));
+        assert!(!super::matches_dns("*.com", "example.com"));
+        assert!(!super::matches_dns("influx*.example.com", "influx1.example.com"));
+    }
+}
+
+/// A plain HTTP client whose requests time out
+pub fn client() -> Client {
+    Client::with_connector(Timeouts(HttpConnector))
+}
+
+/// An HTTPS client whose requests time out, and which only talks to a
+/// server with a certificate `context` trusts that was issued for `host`
+pub fn https_client(mut context: SslContext, host: &str) -> Client {
+    context.set_verify_with_data(SSL_VERIFY_PEER, verify_peer, host.to_string());
+    Client::with_connector(HttpsConnector::new(VerifiedSsl { context: Arc::new(context) }))
+}
+
+/// POST `body` to `url` with `headers` as well as its `content_type`,
+/// returning the status and whatever the server said along with it.  The
+/// response is read to the end so that the connection can be reused.
+pub fn post(client: &Client, url: &str, mut headers: Headers, content_type: &str, body: &[u8])
+            -> Result<(StatusCode, String), String> {
+    headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+    let mut res = try!(client.post(url)
+                             .headers(headers)
+                             .body(body)
+                             .send()
+                             .map_err(|e| e.to_string()));
+    let mut text = String::new();
+    let _ = res.read_to_string(&mut text);
+    Ok((res.status, text.trim().to_string()))
+}
+
+fn set_timeouts(stream: &HttpStream) -> hyper::Result<()> {
+    let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+    try!(stream.0.set_read_timeout(timeout));
+    try!(stream.0.set_write_timeout(timeout));
+    Ok(())
+}
+
+struct Timeouts(HttpConnector);
+
+impl NetworkConnector for Timeouts {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        let stream = try!(self.0.connect(host, port, scheme));
+        try!(set_timeouts(&stream));
+        Ok(stream)
+    }
+}
+
+// hyper's `Openssl`, with the timeouts on the TCP connection before the
+// handshake so that they cover it too, and the host name sent for servers
+// with a certificate per name
+#[derive(Clone)]
+struct VerifiedSsl {
+    context: Arc<SslContext>,
+}
+
+impl net::Ssl for VerifiedSsl {
+    type Stream = SslStream<HttpStream>;
+
+    fn wrap_client(&self, stream: HttpStream, host: &str) -> hyper::Result<SslStream<HttpStream>> {
+        try!(set_timeouts(&stream));
+        let ssl = try!(Ssl::new(&self.context));
+        try!(ssl.set_hostname(host));
+        SslStream::connect(ssl, stream).map_err(From::from)
+    }
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<SslStream<HttpStream>> {
+        SslStream::accept(&*self.context, stream).map_err(From::from)
+    }
+}
+
+// OpenSSL checks the chain, but not who the certificate at the end of it
+// was issued to
+fn verify_peer(preverified: bool, context: &X509StoreContext, host: &String) -> bool {
+    if !preverified || context.error_depth() != 0 {
+        return preverified;
+    }
+    match context.get_current_cert() {
+        Some(cert) => {
+            let named = names_host(&cert, host);
+            if !named {
+                warn!("The certificate presented by {} was issued for some other host", host);
+            }
+            named
+        },
+        None => false,
+    }
+}
+
+// Whether `host` is one of the certificate's alternative names, or its
+// common name if it has none
+fn names_host(cert: &X509, host: &str) -> bool {
+    let ip = host.parse::<IpAddr>().ok();
+    match cert.subject_alt_names() {
+        Some(names) => {
+            for name in &names {
+                let matched = match ip {
+                    Some(ref ip) => name.ipaddress().map_or(false, |octets| ip_octets(ip) == octets),
+                    None => name.dnsname().map_or(false, |pattern| matches_dns(pattern, host)),
+                };
+                if matched {
+                    return true;
+                }
+            }
+            false
+        },
+        None => {
+            ip.is_none() && cert.subject_name().text_by_nid(Nid::CN).map_or(false, |cn| matches_dns(&cn, host))
+        },
+    }
+}
+
+fn ip_octets(ip: &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(ref ip) => ip.octets().to_vec(),
+        IpAddr::V6(ref ip) => ip.segments().iter().flat_map(|s| vec![(s >> 8) as u8, *s as u8]).collect(),
+    }
+}
+
+// Names compare without regard to case, and a `*` may stand for the whole
+// of the leftmost label as long as at least two labels follow it
+fn matches_dns(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_right_matches('.').to_lowercase();
+    let host = host.trim_right_matches('.').to_lowercase();
+    if !pattern.starts_with("*.") {
+        return !pattern.contains('*') && pattern == host;
+    }
+    let suffix = &pattern[1..];
+    if suffix[1..].find('.').is_none() {
+        return false;
+    }
+    match host.find('.') {
+        Some(dot) => dot > 0 && &host[dot..] == suffix,
+        None => false,
+    }
+}
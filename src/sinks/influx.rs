@@ -0,0 +1,579 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread;
+
+use hyper::Client;
+use hyper::header::{Authorization, Basic, Headers};
+use openssl::ssl::{SslContext, SslMethod};
+use openssl::x509::X509FileType;
+
+use config::{Influx, InfluxApi};
+use metric::{Metric, Precision, Value};
+use sinks::{self, Sink, http, now_ms};
+use sinks::spool::Spool;
+
+// Spooled batches replayed at a time, so new measurements aren't held up
+// for long when there's a backlog
+const REPLAY_BATCHES: usize = 10;
+
+// Batches waiting for the writer.  Any more and InfluxDB is too slow or
+// down, so new batches go straight to the spool.
+const QUEUE_BATCHES: usize = 8;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use config::{Influx, InfluxApi};
+    use metric::{Metric, Precision, Value};
+    use sinks::Sink;
+    use sinks::mock;
+    use sinks::spool::Spool;
+
+    fn influx(port: u16, batch_size: u64) -> Influx {
+        let spool = mock::scratch_dir("influx");
+        Influx {
+            port: port,
+            batch_size: batch_size,
+            flush_ms: 60000,
+            spool_path: spool.to_string_lossy().into_owned(),
+            spool_max_mb: 1,
+            ..Influx::default()
+        }
+    }
+
+    fn metric() -> Metric {
+        let mut metric = Metric::new("osd_operation");
+        metric.add_field("size", Value::Integer(4096));
+        metric
+    }
+
+    // The writer counts a batch once it has been answered, which can be a
+    // moment after the server has seen it
+    fn wait_for_counts(sink: &super::InfluxSink, batched: usize, dropped: usize) {
+        let counts = || (sink.counts.batched.load(Ordering::SeqCst), sink.counts.dropped.load(Ordering::SeqCst));
+        for _ in 0..500 {
+            if counts() == (batched, dropped) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(counts(), (batched, dropped));
+    }
+
+    #[test]
+    fn test_batching() {
+        let (port, requests) = mock::http_server(1);
+        let mut sink = super::InfluxSink::new(&influx(port, 4), "ceph-1", &mut BTreeMap::new()).unwrap();
+        // The counters are buffered along with the first measurement
+        sink.write(&metric());
+        sink.write(&metric());
+        // Neither full nor old enough
+        sink.flush();
+        assert_eq!(sink.buffer.len(), 3);
+        sink.write(&metric());
+
+        let (request_line, body) = requests.recv().unwrap();
+        assert_eq!(request_line, "POST /write?db=ceph&precision=s HTTP/1.1");
+        assert_eq!(body.lines().filter(|l| l.starts_with("osd_operation")).count(), 3);
+        assert_eq!(body.lines().filter(|l| l.starts_with("admin_ceph")).count(), 1);
+        assert!(sink.buffer.is_empty());
+        wait_for_counts(&sink, 4, 0);
+    }
+
+    #[test]
+    fn test_replay() {
+        let (port, requests) = mock::http_server(2);
+        let influx = influx(port, 1);
+        // Left behind while Influx was down, by an agent that was writing
+        // in nanoseconds
+        let mut spool = Spool::new(Path::new(&influx.spool_path), 1024);
+        let seq = spool.reserve();
+        spool.push(seq, "/write?db=ceph&precision=ns", "osd_daemon ops=1i 1458000000000000000\n").unwrap();
+
+        let mut sink = super::InfluxSink::new(&influx, "ceph-1", &mut BTreeMap::new()).unwrap();
+        sink.write(&metric());
+        // What was spooled goes first, where it was meant to go
+        assert_eq!(requests.recv().unwrap(),
+                   ("POST /write?db=ceph&precision=ns HTTP/1.1".to_string(), "osd_daemon ops=1i 1458000000000000000\n".to_string()));
+        let (request_line, body) = requests.recv().unwrap();
+        assert_eq!(request_line, "POST /write?db=ceph&precision=s HTTP/1.1");
+        assert!(body.contains("osd_operation size=4096i"));
+        wait_for_counts(&sink, 3, 0);
+        assert!(sink.spool.lock().unwrap().is_empty());
+        fs::remove_dir_all(&influx.spool_path).unwrap();
+    }
+
+    #[test]
+    fn test_unavailable() {
+        // Nothing listening there any more
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let influx = influx(port, 1);
+        let mut spools = BTreeMap::new();
+        let mut sink = super::InfluxSink::new(&influx, "ceph-1", &mut spools).unwrap();
+        sink.write(&metric());
+        for _ in 0..500 {
+            if !sink.spool.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (_, target, batch) = sink.spool.lock().unwrap().oldest().unwrap().unwrap();
+        assert_eq!(target, Some("/write?db=ceph&precision=s".to_string()));
+        assert_eq!(batch.lines().count(), 2);
+
+        // A reload picks up the same spool rather than opening another
+        let reloaded = super::InfluxSink::new(&influx, "ceph-1", &mut spools).unwrap();
+        let bytes = sink.spool.lock().unwrap().bytes();
+        assert_eq!(reloaded.spool.lock().unwrap().bytes(), bytes);
+        assert_eq!(spools.len(), 1);
+        fs::remove_dir_all(&influx.spool_path).unwrap();
+    }
+
+    #[test]
+    fn test_overflow_order() {
+        // Takes the first batch and hangs on to it until told to give up,
+        // by which time the queue has filled and the rest were spooled
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (release, released) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            let _ = released.recv();
+        });
+        let influx = influx(port, 1);
+        let mut sink = super::InfluxSink::new(&influx, "ceph-1", &mut BTreeMap::new()).unwrap();
+        let batches = super::QUEUE_BATCHES + 4;
+        for size in 0..batches {
+            let mut metric = Metric::new("osd_operation");
+            metric.add_field("size", Value::Integer(size as i64));
+            sink.write(&metric);
+        }
+        release.send(()).unwrap();
+
+        // Counted as queued until it's been written or spooled
+        for _ in 0..500 {
+            if sink.counts.queued.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut spool = sink.spool.lock().unwrap();
+        let mut sizes = Vec::new();
+        while let Some((_, _, batch)) = spool.oldest().unwrap() {
+            let line = batch.lines().find(|l| l.starts_with("osd_operation")).unwrap().to_string();
+            let size = &line[line.find("size=").unwrap() + "size=".len()..];
+            sizes.push(size[..size.find('i').unwrap()].parse::<usize>().unwrap());
+            spool.remove();
+        }
+        assert_eq!(sizes, (0..batches).collect::<Vec<_>>());
+        fs::remove_dir_all(&influx.spool_path).unwrap();
+    }
+
+    #[test]
+    fn test_write_target() {
+        let mut influx = Influx::default();
+        influx.retention_policy = Some("two weeks".to_string());
+        assert_eq!(super::base_url(&influx), "http://127.0.0.1:8086");
+        assert_eq!(super::write_target(&influx), "/write?db=ceph&precision=s&rp=two%20weeks");
+
+        influx.api = InfluxApi::V2;
+        influx.https = true;
+        influx.host = "influx.example.com".to_string();
+        influx.org = Some("storage & ops".to_string());
+        influx.bucket = "ceph/autogen".to_string();
+        influx.precision = Precision::Nanoseconds;
+        assert_eq!(super::base_url(&influx), "https://influx.example.com:8086");
+        assert_eq!(super::write_target(&influx),
+                   "/api/v2/write?org=storage%20%26%20ops&bucket=ceph%2Fautogen&precision=ns");
+    }
+}
+
+/// Buffers measurements and writes them to InfluxDB as line protocol in
+/// batches, once `batch_size` are waiting or the oldest has waited
+/// `flush_ms`.  The writes happen on a thread of their own; batches it
+/// can't keep up with or can't write are spooled to disk and replayed in
+/// order once InfluxDB is back.
+pub struct InfluxSink {
+    precision: Precision,
+    hostname: String,
+    batch_size: usize,
+    flush_ms: u64,
+    // Where the batches are for, should they have to be spooled
+    target: String,
+    jobs: SyncSender<Job>,
+    spool: Arc<Mutex<Spool>>,
+    counts: Arc<Counts>,
+    buffer: Vec<String>,
+    // When the oldest buffered measurement arrived, in ms
+    oldest: u64,
+    last_stats: i64,
+}
+
+// Points written, and points lost to rejected writes or a full spool
+struct Counts {
+    batched: AtomicUsize,
+    dropped: AtomicUsize,
+    // Batches handed to the writer that it hasn't written or spooled yet
+    queued: AtomicUsize,
+}
+
+// What the sink hands its writer thread
+enum Job {
+    // A batch of line protocol, its place in the spool should it have to
+    // be spooled, and the number of points in it
+    Write(u64, String, u64),
+    // Nothing new, but the spool may be due a replay
+    Replay,
+}
+
+// Owns the connection to InfluxDB, so that a slow or unreachable server
+// holds up this thread rather than the pipeline
+struct Writer {
+    base_url: String,
+    // The path and query new batches are written to.  Spooled batches keep
+    // the one they were collected for, which a reload may have changed.
+    target: String,
+    auth: Auth,
+    client: Client,
+    spool: Arc<Mutex<Spool>>,
+    counts: Arc<Counts>,
+}
+
+enum Auth {
+    None,
+    Basic(String, String),
+    Token(String),
+}
+
+// Whether a failed write is worth trying again
+enum Failure {
+    Unavailable(String),
+    Rejected(String),
+}
+
+impl InfluxSink {
+    /// The spool outlives the sink, so that a reload doesn't leave two
+    /// sinks writing to the same directory; `spools` holds one per path
+    pub fn new(influx: &Influx, hostname: &str, spools: &mut BTreeMap<String, Arc<Mutex<Spool>>>)
+               -> Result<InfluxSink, String> {
+        let client = if influx.https {
+            try!(https_client(influx))
+        } else {
+            http::client()
+        };
+        let auth = match (influx.api, &influx.token) {
+            (InfluxApi::V2, &Some(ref token)) => Auth::Token(token.expose().to_string()),
+            (InfluxApi::V1, _) if !influx.user.is_empty() => {
+                Auth::Basic(influx.user.clone(), influx.password.as_ref().map_or(String::new(), |p| p.expose().to_string()))
+            },
+            _ => Auth::None,
+        };
+        let max_bytes = influx.spool_max_mb * 1024 * 1024;
+        let spool = spools.entry(influx.spool_path.clone())
+                          .or_insert_with(|| Arc::new(Mutex::new(Spool::new(Path::new(&influx.spool_path), max_bytes))))
+                          .clone();
+        spool.lock().unwrap().set_max_bytes(max_bytes);
+        let counts = Arc::new(Counts {
+            batched: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+        });
+        let writer = Writer {
+            base_url: base_url(influx),
+            target: write_target(influx),
+            auth: auth,
+            client: client,
+            spool: spool.clone(),
+            counts: counts.clone(),
+        };
+        let (jobs, queue) = sync_channel(QUEUE_BATCHES);
+        thread::spawn(move || writer.run(queue));
+        Ok(InfluxSink {
+            precision: influx.precision,
+            hostname: hostname.to_string(),
+            batch_size: influx.batch_size as usize,
+            flush_ms: influx.flush_ms,
+            target: write_target(influx),
+            jobs: jobs,
+            spool: spool,
+            counts: counts,
+            buffer: Vec::new(),
+            oldest: 0,
+            last_stats: 0,
+        })
+    }
+
+    fn push(&mut self, metric: &Metric) {
+        let line = match metric.to_line_protocol_in(self.precision) {
+            Some(line) => line,
+            None => {
+                self.counts.dropped.fetch_add(1, Ordering::SeqCst);
+                debug!("Dropping a {} point without any fields", metric.name);
+                return;
+            },
+        };
+        if self.buffer.is_empty() {
+            self.oldest = now_ms();
+        }
+        self.buffer.push(line);
+    }
+
+    // How the batching is going, as of the last write
+    fn stats(&self) -> Metric {
+        let mut stats = sinks::stats(&self.hostname, "influx",
+                                     &[("points_batched", self.counts.batched.load(Ordering::SeqCst) as u64),
+                                       ("points_dropped", self.counts.dropped.load(Ordering::SeqCst) as u64)]);
+        stats.add_field("spool_bytes", Value::Integer(self.spool.lock().unwrap().bytes() as i64));
+        stats
+    }
+
+    // Hand the buffer to the writer, or spool it if the writer is still
+    // busy with earlier batches.  Its place in the spool is taken now, so
+    // that the batches still queued go in front of it if they're spooled
+    // too.
+    fn send(&mut self) {
+        let batch = mem::replace(&mut self.buffer, Vec::new());
+        if batch.is_empty() {
+            return;
+        }
+        let points = batch.len() as u64;
+        let body = batch.join("\n") + "\n";
+        let seq = self.spool.lock().unwrap().reserve();
+        self.counts.queued.fetch_add(1, Ordering::SeqCst);
+        let job = match self.jobs.try_send(Job::Write(seq, body, points)) {
+            Ok(_) => return,
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => job,
+        };
+        self.counts.queued.fetch_sub(1, Ordering::SeqCst);
+        if let Job::Write(seq, body, points) = job {
+            debug!("The influx writer is behind, spooling {} points", points);
+            spool(&self.spool, seq, &self.target, &body, points, &self.counts);
+        }
+    }
+}
+
+impl Writer {
+    // Until the sink has gone and everything it queued has been written
+    fn run(self, queue: Receiver<Job>) {
+        for job in queue.iter() {
+            if let Job::Write(seq, body, points) = job {
+                self.write(seq, &body, points);
+                self.counts.queued.fetch_sub(1, Ordering::SeqCst);
+            }
+            self.replay();
+        }
+    }
+
+    fn write(&self, seq: u64, body: &str, points: u64) {
+        let spooling = !self.spool.lock().unwrap().is_empty();
+        if !spooling {
+            match self.post(&self.target, body) {
+                Ok(_) => {
+                    self.counts.batched.fetch_add(points as usize, Ordering::SeqCst);
+                    return;
+                },
+                Err(Failure::Rejected(e)) => {
+                    self.counts.dropped.fetch_add(points as usize, Ordering::SeqCst);
+                    warn!("Influx rejected {} points: {}", points, e);
+                    return;
+                },
+                Err(Failure::Unavailable(e)) => {
+                    let mut spool = self.spool.lock().unwrap();
+                    warn!("Unable to write to influx, spooling to {}: {}", spool.dir().display(), e);
+                    spool.failed();
+                },
+            }
+        }
+        // Behind anything older that's already waiting, and in front of
+        // anything newer the sink spooled while this was queued, so it's
+        // all written in order
+        spool(&self.spool, seq, &self.target, body, points, &self.counts);
+    }
+
+    fn replay(&self) {
+        for _ in 0..REPLAY_BATCHES {
+            // Not held while posting, the sink spools through it too
+            let (path, target, oldest) = {
+                let mut spool = self.spool.lock().unwrap();
+                // Batches still queued are older than any the sink spooled
+                // since, and have to be spooled in front of them first
+                if spool.is_empty() || !spool.due() || self.counts.queued.load(Ordering::SeqCst) > 0 {
+                    return;
+                }
+                match spool.oldest() {
+                    Ok(Some(oldest)) => oldest,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Unable to read the oldest batch in {}, skipping it: {}", spool.dir().display(), e);
+                        spool.remove();
+                        continue;
+                    },
+                }
+            };
+            let points = oldest.lines().count() as u64;
+            // Batches spooled before they said where they were for were
+            // all for where this writer writes, unless it was reloaded
+            let result = self.post(target.as_ref().unwrap_or(&self.target), &oldest);
+            let mut spool = self.spool.lock().unwrap();
+            match result {
+                Ok(_) => {
+                    self.counts.batched.fetch_add(points as usize, Ordering::SeqCst);
+                    spool.remove_if_oldest(&path);
+                    spool.succeeded();
+                    if spool.is_empty() {
+                        info!("Caught up with the influx spool");
+                    }
+                },
+                Err(Failure::Rejected(e)) => {
+                    self.counts.dropped.fetch_add(points as usize, Ordering::SeqCst);
+                    warn!("Influx rejected {} spooled points: {}", points, e);
+                    spool.remove_if_oldest(&path);
+                },
+                Err(Failure::Unavailable(e)) => {
+                    debug!("Influx is still unavailable: {}", e);
+                    spool.failed();
+                    return;
+                },
+            }
+        }
+    }
+
+    fn post(&self, target: &str, body: &str) -> Result<(), Failure> {
+        let mut headers = Headers::new();
+        match self.auth {
+            Auth::None => {},
+            Auth::Basic(ref user, ref password) => {
+                headers.set(Authorization(Basic {
+                    username: user.clone(),
+                    password: Some(password.clone()),
+                }));
+            },
+            Auth::Token(ref token) => headers.set_raw("Authorization", vec![format!("Token {}", token).into_bytes()]),
+        }
+        let url = format!("{}{}", self.base_url, target);
+        let (status, text) = try!(http::post(&self.client, &url, headers, "text/plain; charset=utf-8", body.as_bytes())
+                                      .map_err(Failure::Unavailable));
+        if status.is_success() {
+            Ok(())
+        } else if status.to_u16() == 400 {
+            // Bad points, sending them again won't help
+            Err(Failure::Rejected(format!("{}: {}", status, text)))
+        } else {
+            Err(Failure::Unavailable(format!("{}: {}", status, text)))
+        }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn write(&mut self, metric: &Metric) {
+        if sinks::stats_due(&mut self.last_stats) {
+            let stats = self.stats();
+            self.push(&stats);
+        }
+        self.push(metric);
+        if self.buffer.len() >= self.batch_size {
+            self.send();
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.buffer.is_empty() && now_ms() - self.oldest >= self.flush_ms {
+            self.send();
+        } else if !self.spool.lock().unwrap().is_empty() {
+            // Already queued if the writer is busy
+            let _ = self.jobs.try_send(Job::Replay);
+        }
+    }
+}
+
+impl Drop for InfluxSink {
+    // The sinks are rebuilt when the config is reloaded, don't lose what's
+    // waiting.  The writer carries on until it has written everything that
+    // was queued.
+    fn drop(&mut self) {
+        self.send();
+    }
+}
+
+// Add a batch for `target` to the spool in the place `seq` it was given,
+// counting whatever is dropped to make room for it
+fn spool(spool: &Mutex<Spool>, seq: u64, target: &str, body: &str, points: u64, counts: &Counts) {
+    let mut spool = spool.lock().unwrap();
+    match spool.push(seq, target, body) {
+        Ok(0) => {},
+        Ok(dropped) => {
+            counts.dropped.fetch_add(dropped as usize, Ordering::SeqCst);
+            warn!("The influx spool is full, dropped its oldest {} points", dropped);
+        },
+        Err(e) => {
+            counts.dropped.fetch_add(points as usize, Ordering::SeqCst);
+            warn!("Unable to spool {} points to {}: {}", points, spool.dir().display(), e);
+        },
+    }
+}
+
+fn base_url(influx: &Influx) -> String {
+    let scheme = if influx.https { "https" } else { "http" };
+    format!("{}://{}:{}", scheme, influx.host, influx.port)
+}
+
+// Where on the server to POST line protocol for the configured API version,
+// database or bucket and precision
+fn write_target(influx: &Influx) -> String {
+    match influx.api {
+        InfluxApi::V1 => {
+            let mut target = format!("/write?db={}&precision={}", encode(&influx.database), influx.precision.name());
+            if let Some(ref rp) = influx.retention_policy {
+                target.push_str(&format!("&rp={}", encode(rp)));
+            }
+            target
+        },
+        InfluxApi::V2 => {
+            format!("/api/v2/write?org={}&bucket={}&precision={}",
+                    encode(influx.org.as_ref().map_or("", |o| &o[..])),
+                    encode(&influx.bucket),
+                    influx.precision.name())
+        },
+    }
+}
+
+// Checks the server's certificate against `ca_file`, or the system's CAs,
+// and that it's for `host`, and presents a client certificate if there is
+// one
+fn https_client(influx: &Influx) -> Result<Client, String> {
+    let mut context = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| e.to_string()));
+    match influx.ca_file {
+        Some(ref ca_file) => try!(context.set_CA_file(ca_file).map_err(|e| format!("{}: {}", ca_file, e))),
+        None => try!(context.set_default_verify_paths().map_err(|e| e.to_string())),
+    }
+    if let (&Some(ref cert_file), &Some(ref key_file)) = (&influx.cert_file, &influx.key_file) {
+        try!(context.set_certificate_file(cert_file, X509FileType::PEM).map_err(|e| format!("{}: {}", cert_file, e)));
+        try!(context.set_private_key_file(key_file, X509FileType::PEM).map_err(|e| format!("{}: {}", key_file, e)));
+        try!(context.check_private_key().map_err(|e| format!("{} doesn't match {}: {}", key_file, cert_file, e)));
+    }
+    Ok(http::https_client(context, &influx.host))
+}
+
+// Percent encode everything but the unreserved characters of a URL
+fn encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
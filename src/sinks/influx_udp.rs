@@ -0,0 +1,187 @@
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+
+use config::InfluxUdp;
+use metric::Metric;
+use sinks::{self, Sink, MAX_DATAGRAM_BYTES};
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use config::InfluxUdp;
+    use metric::{Metric, Value};
+    use sinks::Sink;
+
+    fn op(size: i64) -> Metric {
+        let mut metric = Metric::new("osd_operation");
+        metric.timestamp.sec = 1458000000;
+        metric.timestamp.nsec = 0;
+        metric.add_tag("type", "write");
+        metric.add_field("size", Value::Integer(size));
+        metric
+    }
+
+    #[test]
+    fn test_datagrams() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut influx_udp = InfluxUdp::default();
+        influx_udp.port = listener.local_addr().unwrap().port();
+
+        let mut sink = super::InfluxUdpSink::new(&influx_udp, "ceph-1");
+        // Already sent its counters, leave them out
+        sink.last_stats = ::time::get_time().sec;
+        // Room for two lines of 52 bytes and their newlines
+        sink.max_bytes = 110;
+        sink.write(&op(1));
+        sink.write(&op(2));
+        sink.write(&op(3));
+        sink.flush();
+
+        let mut buf = [0u8; 2048];
+        let len = listener.recv_from(&mut buf).unwrap().0;
+        assert_eq!(&buf[..len], &b"osd_operation,type=write size=1i 1458000000000000000\n\
+                                  osd_operation,type=write size=2i 1458000000000000000\n"[..]);
+        let len = listener.recv_from(&mut buf).unwrap().0;
+        assert_eq!(&buf[..len], &b"osd_operation,type=write size=3i 1458000000000000000\n"[..]);
+        assert_eq!((sink.sent, sink.dropped), (3, 0));
+
+        // Too big for any datagram
+        sink.max_bytes = 20;
+        sink.write(&op(4));
+        assert_eq!(sink.dropped, 1);
+    }
+
+    #[test]
+    fn test_ipv6() {
+        // Not every host has IPv6, even on the loopback
+        let listener = match UdpSocket::bind("[::1]:0") {
+            Ok(listener) => listener,
+            Err(_) => return,
+        };
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut influx_udp = InfluxUdp::default();
+        influx_udp.host = "::1".to_string();
+        influx_udp.port = listener.local_addr().unwrap().port();
+
+        let mut sink = super::InfluxUdpSink::new(&influx_udp, "ceph-1");
+        sink.last_stats = ::time::get_time().sec;
+        sink.write(&op(1));
+        sink.flush();
+        let mut buf = [0u8; 2048];
+        let len = listener.recv_from(&mut buf).unwrap().0;
+        assert_eq!(&buf[..len], &b"osd_operation,type=write size=1i 1458000000000000000\n"[..]);
+        assert_eq!(sink.socket.as_ref().map(|&(_, addr)| addr), Some(listener.local_addr().unwrap()));
+    }
+}
+
+/// Writes line protocol to an InfluxDB UDP listener, packing as many lines
+/// into each datagram as fit under the MTU.  There's no telling whether
+/// they arrive, only whether they could be sent.
+pub struct InfluxUdpSink {
+    influx_udp: InfluxUdp,
+    hostname: String,
+    // Opened for the address `host` resolved to, which is looked up again
+    // once a send fails
+    socket: Option<(UdpSocket, SocketAddr)>,
+    max_bytes: usize,
+    // Lines waiting to fill a datagram, and how many
+    datagram: String,
+    lines: u64,
+    // Lines sent, and lines lost to failed sends or being too big
+    sent: u64,
+    dropped: u64,
+    last_stats: i64,
+}
+
+impl InfluxUdpSink {
+    pub fn new(influx_udp: &InfluxUdp, hostname: &str) -> InfluxUdpSink {
+        InfluxUdpSink {
+            influx_udp: influx_udp.clone(),
+            hostname: hostname.to_string(),
+            socket: None,
+            max_bytes: MAX_DATAGRAM_BYTES,
+            datagram: String::new(),
+            lines: 0,
+            sent: 0,
+            dropped: 0,
+            last_stats: 0,
+        }
+    }
+
+    fn add(&mut self, metric: &Metric) {
+        let line = match metric.to_line_protocol_in(self.influx_udp.precision) {
+            Some(line) => line + "\n",
+            None => {
+                self.dropped += 1;
+                debug!("Dropping a {} point without any fields", metric.name);
+                return;
+            },
+        };
+        if line.len() > self.max_bytes {
+            self.dropped += 1;
+            debug!("Dropping a {} byte {} point, too big for a datagram", line.len(), metric.name);
+            return;
+        }
+        if self.datagram.len() + line.len() > self.max_bytes {
+            self.send();
+        }
+        self.datagram.push_str(&line);
+        self.lines += 1;
+    }
+
+    fn send(&mut self) {
+        if self.datagram.is_empty() {
+            return;
+        }
+        let datagram = mem::replace(&mut self.datagram, String::new());
+        let lines = mem::replace(&mut self.lines, 0);
+        if self.socket.is_none() {
+            match sinks::open_udp(&self.influx_udp.host, self.influx_udp.port) {
+                Ok(socket) => self.socket = Some(socket),
+                Err(e) => {
+                    self.dropped += lines;
+                    debug!("Unable to open a socket for the influx UDP listener at {}:{}: {}",
+                           self.influx_udp.host, self.influx_udp.port, e);
+                    return;
+                }
+            }
+        }
+        let result = match self.socket {
+            Some((ref socket, addr)) => socket.send_to(datagram.as_bytes(), addr),
+            None => return,
+        };
+        match result {
+            Ok(_) => self.sent += lines,
+            Err(e) => {
+                self.dropped += lines;
+                // The host may have moved, look it up again next time
+                self.socket = None;
+                debug!("Unable to send to influx at {}:{}: {}", self.influx_udp.host, self.influx_udp.port, e);
+            }
+        }
+    }
+}
+
+impl Sink for InfluxUdpSink {
+    fn write(&mut self, metric: &Metric) {
+        if sinks::stats_due(&mut self.last_stats) {
+            let stats = sinks::stats(&self.hostname, "influx_udp", &[("points_sent", self.sent),
+                                                                     ("points_dropped", self.dropped)]);
+            self.add(&stats);
+        }
+        self.add(metric);
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+}
+
+impl Drop for InfluxUdpSink {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
@@ -0,0 +1,66 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, channel};
+use std::thread;
+
+use time;
+
+/// A new empty directory for a test to write to
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("admin_ceph_{}_{}", name, time::precise_time_ns()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// An HTTP server for sink tests that answers `requests` requests with a
+/// 204 and hands back each request line and body
+pub fn http_server(requests: usize) -> (u16, Receiver<(String, String)>) {
+    let (tx, rx) = channel();
+    let port = serve(requests, move |request_line, body| {
+        tx.send((request_line, String::from_utf8(body).unwrap())).unwrap();
+    });
+    (port, rx)
+}
+
+/// The same for binary bodies
+pub fn http_server_bytes(requests: usize) -> (u16, Receiver<(String, Vec<u8>)>) {
+    let (tx, rx) = channel();
+    let port = serve(requests, move |request_line, body| tx.send((request_line, body)).unwrap());
+    (port, rx)
+}
+
+fn serve<F>(requests: usize, received: F) -> u16
+    where F: Fn(String, Vec<u8>) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for _ in 0..requests {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                let lower = header.to_lowercase();
+                if lower.starts_with("content-length:") {
+                    length = lower["content-length:".len()..].trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = reader.into_inner();
+            stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").unwrap();
+            received(request_line.trim().to_string(), body);
+        }
+    });
+    port
+}
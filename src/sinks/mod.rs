@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, TryRecvError, channel};
+use std::thread;
+use std::time::Duration;
+
+use time;
+
+use config::{Args, SharedArgs};
+use metric::{Metric, Value};
+
+mod connection;
+mod file;
+mod forward;
+mod graphite;
+mod http;
+mod influx;
+mod influx_udp;
+#[cfg(test)]
+mod mock;
+mod opentsdb;
+mod otlp;
+mod prometheus;
+mod route;
+mod spool;
+mod statsd;
+mod stdout;
+
+// Captured packets are events rather than readings, so sinks that know the
+// difference count them
+const PACKET_MEASUREMENT: &'static str = "osd_operation";
+
+// Keeps datagrams inside a typical MTU
+const MAX_DATAGRAM_BYTES: usize = 1432;
+
+// How long the pipeline waits for measurements between flushes
+const IDLE_MS: u64 = 100;
+
+// How often sinks that count what they send write their counters along with
+// everything else
+const STATS_SECS: i64 = 60;
+
+/// Somewhere measurements are sent.  Every sink named in `outputs:` gets
+/// every measurement from every collector.
+pub trait Sink {
+    fn write(&mut self, metric: &Metric);
+
+    /// Called whenever no measurements are waiting, so sinks that buffer
+    /// can send anything that has waited long enough
+    fn flush(&mut self) {}
+}
+
+// Sinks are rebuilt whenever the config is reloaded.  The Prometheus
+// exporter's listener is started the first time it's needed and kept, as
+// are the Influx spools.
+fn from_args(args: &Args,
+             exporter: &mut Option<prometheus::Exporter>,
+             spools: &mut BTreeMap<String, Arc<Mutex<spool::Spool>>>)
+             -> Vec<Box<Sink>> {
+    let mut sinks: Vec<Box<Sink>> = Vec::new();
+    for output in args.outputs.iter() {
+        let sink: Box<Sink> = match &output[..] {
+            "stdout" => Box::new(stdout::StdoutSink::new(&args.stdout)),
+            "prometheus" => {
+                if exporter.is_none() {
+                    match prometheus::Exporter::start(&args.prometheus) {
+                        Ok(e) => *exporter = Some(e),
+                        Err(e) => error!("Unable to listen on {} for Prometheus: {}", args.prometheus.listen, e),
+                    }
+                }
+                match *exporter {
+                    Some(ref exporter) => Box::new(exporter.sink(&args.prometheus)),
+                    None => continue,
+                }
+            },
+            "graphite" => Box::new(graphite::GraphiteSink::new(&args.graphite)),
+            "statsd" => Box::new(statsd::StatsdSink::new(&args.statsd, &args.graphite)),
+            "opentsdb" => Box::new(opentsdb::OpenTsdbSink::new(&args.opentsdb)),
+            "file" => Box::new(file::FileSink::new(&args.file)),
+            "forward" => Box::new(forward::ForwardSink::new(&args.forward)),
+            "otlp" => Box::new(otlp::OtlpSink::new(&args.otlp)),
+            "influx_udp" => Box::new(influx_udp::InfluxUdpSink::new(&args.influx_udp, &args.hostname)),
+            "influx" => {
+                let influx = match args.influx {
+                    Some(ref influx) => influx,
+                    None => continue,
+                };
+                match influx::InfluxSink::new(influx, &args.hostname, spools) {
+                    Ok(sink) => Box::new(sink),
+                    Err(e) => {
+                        error!("Unable to set up TLS for InfluxDB at {}: {}", influx.host, e);
+                        continue;
+                    }
+                }
+            },
+            _ => continue,
+        };
+        match args.routes.get(output) {
+            Some(route) => sinks.push(Box::new(route::RoutedSink::new(route, sink))),
+            None => sinks.push(sink),
+        }
+    }
+    sinks
+}
+
+// An `admin_ceph` measurement of how `output` is doing since the agent
+// started
+fn stats(hostname: &str, output: &str, counters: &[(&str, u64)]) -> Metric {
+    let mut stats = Metric::new("admin_ceph");
+    stats.add_tag("hostname", hostname);
+    stats.add_tag("output", output);
+    for &(name, value) in counters.iter() {
+        stats.add_counter(name, Value::Integer(value as i64));
+    }
+    stats
+}
+
+// Whether it's time for the stats again since `last`, in which case it
+// becomes now
+fn stats_due(last: &mut i64) -> bool {
+    let now = time::get_time().sec;
+    if now - *last >= STATS_SECS {
+        *last = now;
+        true
+    } else {
+        false
+    }
+}
+
+// A monotonic clock for batching and backoff
+fn now_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
+// The first address `host` resolves to, looked up once by the caller rather
+// than for every datagram or connection
+fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    match try!((host, port).to_socket_addrs()).next() {
+        Some(addr) => Ok(addr),
+        None => Err(io::Error::new(io::ErrorKind::Other, "no addresses found")),
+    }
+}
+
+// A socket to send datagrams to `host` from, bound for the address family
+// it resolved to
+fn open_udp(host: &str, port: u16) -> io::Result<(UdpSocket, SocketAddr)> {
+    let addr = try!(resolve(host, port));
+    let socket = match addr {
+        SocketAddr::V4(_) => try!(UdpSocket::bind("0.0.0.0:0")),
+        SocketAddr::V6(_) => try!(UdpSocket::bind("[::]:0")),
+    };
+    Ok((socket, addr))
+}
+
+/// Start the thread that fans measurements out to the sinks.  Collectors
+/// send into the returned channel.
+pub fn initialize_pipeline(shared: &Arc<SharedArgs>) -> Sender<Metric> {
+    let (tx, rx) = channel::<Metric>();
+    let shared = shared.clone();
+    thread::spawn(move || {
+        debug!("Pipeline thread active");
+        let mut exporter = None;
+        let mut spools = BTreeMap::new();
+        loop {
+            let generation = shared.generation();
+            let args = shared.current();
+            let mut sinks = from_args(&args, &mut exporter, &mut spools);
+            while shared.generation() == generation {
+                let mut metric = match rx.try_recv() {
+                    Ok(metric) => metric,
+                    Err(TryRecvError::Empty) => {
+                        for sink in sinks.iter_mut() {
+                            sink.flush();
+                        }
+                        thread::sleep(Duration::from_millis(IDLE_MS));
+                        continue;
+                    },
+                    // Every collector has gone away
+                    Err(TryRecvError::Disconnected) => return,
+                };
+                for (key, value) in args.tags.iter() {
+                    metric.tags.entry(key.clone()).or_insert(value.clone());
+                }
+                for sink in sinks.iter_mut() {
+                    sink.write(&metric);
+                }
+            }
+            debug!("Pipeline thread picking up new configuration");
+        }
+    });
+    tx
+}
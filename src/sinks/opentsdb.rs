@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use hyper::Client;
+use hyper::header::Headers;
+use rustc_serialize::json::Json;
+
+use config::{OpenTsdb, OpenTsdbProtocol};
+use metric::{Metric, Value};
+use sinks::{Sink, http};
+use sinks::connection::Connection;
+
+// Datapoints per put, much larger bodies need OpenTSDB's
+// `tsd.http.request.enable_chunked`
+const MAX_BATCH: usize = 50;
+
+#[cfg(test)]
+mod tests {
+    use config::OpenTsdb;
+    use metric::{Metric, Value};
+    use sinks::Sink;
+    use sinks::mock;
+
+    fn metric() -> Metric {
+        let mut metric = Metric::new("osd_daemon");
+        metric.timestamp.sec = 1458000000;
+        metric.add_tag("hostname", "ceph-1");
+        metric.add_tag("drive_name", "");
+        metric.add_tag("osd_num", "3");
+        metric.add_field("ops", Value::Integer(4889));
+        metric.add_field("op_latency", Value::Float(0.5));
+        metric
+    }
+
+    #[test]
+    fn test_http_put() {
+        let (port, requests) = mock::http_server(1);
+        let mut opentsdb = OpenTsdb::default();
+        opentsdb.port = port;
+        let mut sink = super::OpenTsdbSink::new(&opentsdb);
+        sink.write(&metric());
+        let mut later = metric();
+        later.timestamp.sec = 1458000005;
+        later.fields.remove("op_latency");
+        sink.write(&later);
+        sink.flush();
+
+        // Both measurements in the one put
+        let (request_line, body) = requests.recv().unwrap();
+        assert_eq!(request_line, "POST /api/put HTTP/1.1");
+        assert_eq!(body,
+                   r#"[{"metric":"ceph.osd_daemon.op_latency","tags":{"drive_name":"unknown","hostname":"ceph-1","osd_num":"3"},"timestamp":1458000000,"value":0.5},"#.to_string() +
+                   r#"{"metric":"ceph.osd_daemon.ops","tags":{"drive_name":"unknown","hostname":"ceph-1","osd_num":"3"},"timestamp":1458000000,"value":4889},"# +
+                   r#"{"metric":"ceph.osd_daemon.ops","tags":{"drive_name":"unknown","hostname":"ceph-1","osd_num":"3"},"timestamp":1458000005,"value":4889}]"#);
+    }
+
+    #[test]
+    fn test_http_batch_size() {
+        let (port, requests) = mock::http_server(1);
+        let mut opentsdb = OpenTsdb::default();
+        opentsdb.port = port;
+        let mut sink = super::OpenTsdbSink::new(&opentsdb);
+        // Two datapoints each, the last one over the limit waits for a flush
+        for _ in 0..super::MAX_BATCH / 2 + 1 {
+            sink.write(&metric());
+        }
+
+        let (_, body) = requests.recv().unwrap();
+        assert_eq!(body.matches("\"metric\"").count(), super::MAX_BATCH);
+        assert_eq!(sink.buffer.len(), 2);
+    }
+
+    #[test]
+    fn test_telnet_lines() {
+        let points = super::datapoints("ceph", &metric());
+        assert_eq!(super::telnet_lines(&points, 1458000000),
+                   "put ceph.osd_daemon.op_latency 1458000000 0.5 drive_name=unknown hostname=ceph-1 osd_num=3\n\
+                    put ceph.osd_daemon.ops 1458000000 4889 drive_name=unknown hostname=ceph-1 osd_num=3\n");
+    }
+}
+
+/// Sends each numeric field as an OpenTSDB datapoint, over HTTP in batches
+/// of up to `MAX_BATCH` put whenever the pipeline goes quiet, or as telnet
+/// style `put` lines
+pub struct OpenTsdbSink {
+    opentsdb: OpenTsdb,
+    url: String,
+    client: Client,
+    connection: Connection,
+    // Datapoints waiting for the next put
+    buffer: Vec<Json>,
+}
+
+impl OpenTsdbSink {
+    pub fn new(opentsdb: &OpenTsdb) -> OpenTsdbSink {
+        OpenTsdbSink {
+            opentsdb: opentsdb.clone(),
+            url: format!("http://{}:{}/api/put", opentsdb.host, opentsdb.port),
+            client: http::client(),
+            connection: Connection::new(&opentsdb.host, opentsdb.port),
+            buffer: Vec::new(),
+        }
+    }
+
+    fn send(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let points = mem::replace(&mut self.buffer, Vec::new());
+        if let Err(e) = self.put(&points) {
+            debug!("Unable to send {} datapoints to OpenTSDB: {}", points.len(), e);
+        }
+    }
+
+    fn put(&self, points: &[Json]) -> Result<(), String> {
+        let body = Json::Array(points.to_vec()).to_string();
+        let (status, text) = try!(http::post(&self.client, &self.url, Headers::new(), "application/json", body.as_bytes()));
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, text));
+        }
+        Ok(())
+    }
+}
+
+impl Sink for OpenTsdbSink {
+    fn write(&mut self, metric: &Metric) {
+        let points = datapoints(&self.opentsdb.prefix, metric);
+        if points.is_empty() {
+            return;
+        }
+        match self.opentsdb.protocol {
+            OpenTsdbProtocol::Http => {
+                for point in points.iter() {
+                    self.buffer.push(point.to_json(metric.timestamp.sec));
+                    if self.buffer.len() >= MAX_BATCH {
+                        self.send();
+                    }
+                }
+            },
+            OpenTsdbProtocol::Telnet => {
+                let lines = telnet_lines(&points, metric.timestamp.sec);
+                if let Err(e) = self.connection.send(lines.as_bytes()) {
+                    debug!("Unable to send {} to OpenTSDB: {}", metric.name, e);
+                }
+            },
+        }
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+}
+
+impl Drop for OpenTsdbSink {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
+
+struct Datapoint {
+    metric: String,
+    value: Json,
+    tags: BTreeMap<String, String>,
+}
+
+impl Datapoint {
+    fn to_json(&self, timestamp: i64) -> Json {
+        let mut tags = BTreeMap::new();
+        for (key, value) in self.tags.iter() {
+            tags.insert(key.clone(), Json::String(value.clone()));
+        }
+        let mut object = BTreeMap::new();
+        object.insert("metric".to_string(), Json::String(self.metric.clone()));
+        object.insert("timestamp".to_string(), Json::I64(timestamp));
+        object.insert("value".to_string(), self.value.clone());
+        object.insert("tags".to_string(), Json::Object(tags));
+        Json::Object(object)
+    }
+}
+
+// One datapoint per numeric field, all with the metric's tags
+fn datapoints(prefix: &str, metric: &Metric) -> Vec<Datapoint> {
+    let mut tags = BTreeMap::new();
+    for (key, value) in metric.tags.iter() {
+        tags.insert(sanitize(key), sanitize(value));
+    }
+    let mut points = Vec::new();
+    for (key, value) in metric.fields.iter() {
+        let value = match *value {
+            Value::Integer(i) => Json::I64(i),
+            Value::Float(f) if f.is_finite() => Json::F64(f),
+            Value::Boolean(b) => Json::I64(if b { 1 } else { 0 }),
+            _ => continue,
+        };
+        points.push(Datapoint {
+            metric: format!("{}.{}.{}", prefix, sanitize(&metric.name), sanitize(key)),
+            value: value,
+            tags: tags.clone(),
+        });
+    }
+    points
+}
+
+fn telnet_lines(points: &[Datapoint], timestamp: i64) -> String {
+    let mut lines = String::new();
+    for point in points.iter() {
+        lines.push_str(&format!("put {} {} {}", point.metric, timestamp, point.value));
+        for (key, value) in point.tags.iter() {
+            lines.push_str(&format!(" {}={}", key, value));
+        }
+        lines.push('\n');
+    }
+    lines
+}
+
+// OpenTSDB only takes letters, numbers, `-`, `_`, `.` and `/`, and no empty
+// tag values
+fn sanitize(s: &str) -> String {
+    if s.is_empty() {
+        return "unknown".to_string();
+    }
+    s.chars().map(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' | '.' | '/' => c,
+        _ => '_',
+    }).collect()
+}
@@ -0,0 +1,451 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+
+use hyper::Client;
+use hyper::header::Headers;
+
+use config::Otlp;
+use metric::{Metric, Value};
+use sinks::{Sink, http};
+
+// Export once this many measurements are waiting, rather than waiting for
+// the pipeline to go quiet
+const MAX_BATCH: usize = 1000;
+
+// AGGREGATION_TEMPORALITY_CUMULATIVE, counters are totals since the
+// daemon started
+const CUMULATIVE: u64 = 2;
+
+// Tags that say where a measurement came from, sent as resource attributes
+// rather than on every data point
+const RESOURCE_TAGS: &'static [(&'static str, &'static str)] = &[("hostname", "host.name"), ("cluster", "ceph.cluster")];
+
+#[cfg(test)]
+mod tests {
+    use config::Otlp;
+    use metric::{Metric, Value};
+    use sinks::{Sink, http};
+    use sinks::mock;
+
+    // Just enough protobuf decoding to look inside an export request
+    enum Field {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn decode(buf: &[u8]) -> Vec<(u64, Field)> {
+        fn varint(buf: &[u8], pos: &mut usize) -> u64 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = buf[*pos];
+                *pos += 1;
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let key = varint(buf, &mut pos);
+            let field = match key & 7 {
+                0 => Field::Varint(varint(buf, &mut pos)),
+                1 => {
+                    let value = (0..8).fold(0, |value, i| value | (buf[pos + i] as u64) << (8 * i));
+                    pos += 8;
+                    Field::Fixed64(value)
+                },
+                2 => {
+                    let len = varint(buf, &mut pos) as usize;
+                    pos += len;
+                    Field::Bytes(buf[pos - len..pos].to_vec())
+                },
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, field));
+        }
+        fields
+    }
+
+    fn messages(buf: &[u8], number: u64) -> Vec<Vec<u8>> {
+        decode(buf).into_iter().filter_map(|(n, field)| match field {
+            Field::Bytes(bytes) if n == number => Some(bytes),
+            _ => None,
+        }).collect()
+    }
+
+    fn message(buf: &[u8], number: u64) -> Vec<u8> {
+        messages(buf, number).pop().unwrap()
+    }
+
+    fn string(buf: &[u8], number: u64) -> String {
+        String::from_utf8(message(buf, number)).unwrap()
+    }
+
+    fn number(buf: &[u8], number: u64) -> u64 {
+        decode(buf).into_iter().filter_map(|(n, field)| match field {
+            Field::Varint(value) | Field::Fixed64(value) if n == number => Some(value),
+            _ => None,
+        }).next().unwrap()
+    }
+
+    fn attributes(buf: &[u8], number: u64) -> Vec<(String, String)> {
+        messages(buf, number).iter().map(|kv| (string(kv, 1), string(&message(kv, 2), 1))).collect()
+    }
+
+    #[test]
+    fn test_export() {
+        let (port, requests) = mock::http_server_bytes(1);
+        let mut otlp = Otlp::default();
+        otlp.port = port;
+        let mut sink = super::OtlpSink::new(&otlp);
+
+        let mut metric = Metric::new("osd_daemon");
+        metric.timestamp.sec = 1458000000;
+        metric.timestamp.nsec = 0;
+        metric.add_tag("hostname", "ceph-1");
+        metric.add_tag("cluster", "ceph");
+        metric.add_tag("osd_num", "3");
+        metric.add_field("stat_bytes", Value::Integer(466472001536));
+        metric.add_counter("ops", Value::Integer(4889));
+        metric.add_counter("op_latency", Value::Float(379.5));
+        metric.add_counter("op_latency_count", Value::Integer(642));
+        sink.write(&metric);
+        sink.flush();
+
+        let (request_line, body) = requests.recv().unwrap();
+        assert_eq!(request_line, "POST /v1/metrics HTTP/1.1");
+        let resource_metrics = messages(&body, 1);
+        assert_eq!(resource_metrics.len(), 1);
+        assert_eq!(attributes(&message(&resource_metrics[0], 1), 1),
+                   vec![("host.name".to_string(), "ceph-1".to_string()), ("ceph.cluster".to_string(), "ceph".to_string())]);
+        let metrics = messages(&message(&resource_metrics[0], 2), 2);
+        let names: Vec<String> = metrics.iter().map(|m| string(m, 1)).collect();
+        assert_eq!(names, vec!["osd_daemon.op_latency", "osd_daemon.ops", "osd_daemon.stat_bytes"]);
+
+        // The latency sum and its count make a histogram without buckets
+        assert_eq!(string(&metrics[0], 3), "s");
+        let histogram = message(&metrics[0], 9);
+        assert_eq!(number(&histogram, 2), super::CUMULATIVE);
+        let point = message(&histogram, 1);
+        assert_eq!(number(&point, 3), 1458000000000000000);
+        assert_eq!(number(&point, 4), 642);
+        assert_eq!(number(&point, 5), unsafe { ::std::mem::transmute::<f64, u64>(379.5) });
+        assert_eq!(attributes(&point, 9), vec![("osd_num".to_string(), "3".to_string())]);
+
+        let sum = message(&metrics[1], 7);
+        assert_eq!(number(&sum, 3), 1);
+        assert_eq!(number(&message(&sum, 1), 6), 4889);
+
+        assert_eq!(string(&metrics[2], 3), "By");
+        let point = message(&message(&metrics[2], 5), 1);
+        assert_eq!(number(&point, 6), 466472001536);
+        assert_eq!(attributes(&point, 7), vec![("osd_num".to_string(), "3".to_string())]);
+    }
+
+    #[test]
+    fn test_start_time() {
+        let mut sink = super::OtlpSink::new(&Otlp::default());
+        assert_eq!(sink.start_time("ops", 1, 10.0), 1);
+        assert_eq!(sink.start_time("ops", 2, 20.0), 1);
+        // The daemon restarted since the last sample
+        assert_eq!(sink.start_time("ops", 3, 5.0), 2);
+        assert_eq!(sink.start_time("ops", 4, 6.0), 2);
+    }
+}
+
+/// Exports measurements to an OpenTelemetry collector over OTLP/HTTP.
+/// Counters become cumulative monotonic sums, except for latency sums with
+/// a `_count` counter alongside, which become histograms without buckets.
+/// Everything else is a gauge.
+pub struct OtlpSink {
+    url: String,
+    client: Client,
+    buffer: Vec<Metric>,
+    // Start time and last time and value of each counter series, so the
+    // start moves when a daemon restarts
+    starts: HashMap<String, (u64, u64, f64)>,
+}
+
+impl OtlpSink {
+    pub fn new(otlp: &Otlp) -> OtlpSink {
+        OtlpSink {
+            url: format!("http://{}:{}{}", otlp.host, otlp.port, otlp.path),
+            client: http::client(),
+            buffer: Vec::new(),
+            starts: HashMap::new(),
+        }
+    }
+
+    fn send(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let metrics = mem::replace(&mut self.buffer, Vec::new());
+        let body = self.export_request(&metrics);
+        if let Err(e) = self.post(&body) {
+            debug!("Unable to export {} measurements to {}: {}", metrics.len(), self.url, e);
+        }
+    }
+
+    fn post(&self, body: &[u8]) -> Result<(), String> {
+        let (status, text) = try!(http::post(&self.client, &self.url, Headers::new(), "application/x-protobuf", body));
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, text));
+        }
+        Ok(())
+    }
+
+    // When the counter `series` started counting up to `value`
+    fn start_time(&mut self, series: &str, time: u64, value: f64) -> u64 {
+        let start = match self.starts.get(series) {
+            Some(&(start, _, last)) if value >= last => start,
+            // Reset some time after the last sample
+            Some(&(_, last_time, _)) => last_time,
+            None => time,
+        };
+        self.starts.insert(series.to_string(), (start, time, value));
+        start
+    }
+
+    // An ExportMetricsServiceRequest, one ResourceMetrics per host and
+    // cluster and one Metric per name within it
+    fn export_request(&mut self, metrics: &[Metric]) -> Vec<u8> {
+        let mut resources: BTreeMap<Vec<(&str, String)>, BTreeMap<String, Series>> = BTreeMap::new();
+        for metric in metrics.iter() {
+            let resource: Vec<(&str, String)> = RESOURCE_TAGS.iter()
+                                                             .filter_map(|&(tag, key)| metric.tags.get(tag).map(|value| (key, value.clone())))
+                                                             .collect();
+            let mut attributes = Vec::new();
+            for (key, value) in metric.tags.iter() {
+                if !RESOURCE_TAGS.iter().any(|&(tag, _)| tag == key) {
+                    attributes.push(key_value(key, value));
+                }
+            }
+            let time = metric.timestamp.sec as u64 * 1000000000 + metric.timestamp.nsec as u64;
+            let series_id = format!("{}{:?}", metric.name, metric.tags);
+            for (key, value) in metric.fields.iter() {
+                let value = match *value {
+                    Value::Integer(i) => Number::Int(i),
+                    Value::Float(f) => Number::Double(f),
+                    Value::Boolean(b) => Number::Int(if b { 1 } else { 0 }),
+                    Value::String(_) => continue,
+                };
+                let count_key = format!("{}_count", key);
+                if key.ends_with("_count") && metric.is_counter(key) && metric.is_counter(&key[..key.len() - 6]) {
+                    // Sent as the histogram's count
+                    continue;
+                }
+                let mut point = Message::new();
+                let kind = if metric.is_counter(key) && metric.is_counter(&count_key) {
+                    let count = match metric.fields.get(&count_key) {
+                        Some(&Value::Integer(count)) => count as u64,
+                        _ => 0,
+                    };
+                    point.fixed64(2, self.start_time(&format!("{}{}", series_id, count_key), time, count as f64));
+                    point.fixed64(3, time);
+                    point.fixed64(4, count);
+                    point.double(5, value.as_f64());
+                    for attribute in attributes.iter() {
+                        point.message(9, attribute);
+                    }
+                    Kind::Histogram
+                } else {
+                    let kind = if metric.is_counter(key) {
+                        point.fixed64(2, self.start_time(&format!("{}{}", series_id, key), time, value.as_f64()));
+                        Kind::Sum
+                    } else {
+                        Kind::Gauge
+                    };
+                    point.fixed64(3, time);
+                    match value {
+                        Number::Double(f) => point.double(4, f),
+                        Number::Int(i) => point.fixed64(6, i as u64),
+                    }
+                    for attribute in attributes.iter() {
+                        point.message(7, attribute);
+                    }
+                    kind
+                };
+                let name = format!("{}.{}", metric.name, key);
+                resources.entry(resource.clone()).or_insert(BTreeMap::new())
+                         .entry(name).or_insert(Series { kind: kind, unit: unit(key), points: Vec::new() })
+                         .points.push(point);
+            }
+        }
+
+        let mut request = Message::new();
+        for (resource, series) in resources.iter() {
+            let mut attributes = Message::new();
+            for &(key, ref value) in resource.iter() {
+                attributes.message(1, &key_value(key, value));
+            }
+            let mut scope = Message::new();
+            scope.string(1, "admin_ceph");
+            scope.string(2, env!("CARGO_PKG_VERSION"));
+            let mut scope_metrics = Message::new();
+            scope_metrics.message(1, &scope);
+            for (name, series) in series.iter() {
+                scope_metrics.message(2, &series.to_message(name));
+            }
+            let mut resource_metrics = Message::new();
+            resource_metrics.message(1, &attributes);
+            resource_metrics.message(2, &scope_metrics);
+            request.message(1, &resource_metrics);
+        }
+        request.buf
+    }
+}
+
+impl Sink for OtlpSink {
+    fn write(&mut self, metric: &Metric) {
+        self.buffer.push(metric.clone());
+        if self.buffer.len() >= MAX_BATCH {
+            self.send();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+}
+
+impl Drop for OtlpSink {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
+
+#[derive(Clone,Copy)]
+enum Number {
+    Int(i64),
+    Double(f64),
+}
+
+impl Number {
+    fn as_f64(&self) -> f64 {
+        match *self {
+            Number::Int(i) => i as f64,
+            Number::Double(f) => f,
+        }
+    }
+}
+
+#[derive(Clone,Copy)]
+enum Kind {
+    Gauge,
+    Sum,
+    Histogram,
+}
+
+// The data points of one metric name
+struct Series {
+    kind: Kind,
+    unit: &'static str,
+    points: Vec<Message>,
+}
+
+impl Series {
+    fn to_message(&self, name: &str) -> Message {
+        let mut data = Message::new();
+        for point in self.points.iter() {
+            data.message(1, point);
+        }
+        let mut metric = Message::new();
+        metric.string(1, name);
+        metric.string(3, self.unit);
+        match self.kind {
+            Kind::Gauge => metric.message(5, &data),
+            Kind::Sum => {
+                data.varint(2, CUMULATIVE);
+                data.varint(3, 1);
+                metric.message(7, &data);
+            },
+            Kind::Histogram => {
+                data.varint(2, CUMULATIVE);
+                metric.message(9, &data);
+            },
+        }
+        metric
+    }
+}
+
+// UCUM units, as OpenTelemetry expects
+fn unit(field: &str) -> &'static str {
+    if field.contains("latency") {
+        "s"
+    } else if field.contains("bytes") {
+        "By"
+    } else {
+        ""
+    }
+}
+
+// A KeyValue with a string AnyValue
+fn key_value(key: &str, value: &str) -> Message {
+    let mut any_value = Message::new();
+    any_value.string(1, value);
+    let mut kv = Message::new();
+    kv.string(1, key);
+    kv.message(2, &any_value);
+    kv
+}
+
+/// A protobuf message being encoded, fields are appended in the order
+/// they're given
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new() -> Message {
+        Message { buf: Vec::new() }
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, number: u64, wire_type: u64) {
+        self.raw_varint(number << 3 | wire_type);
+    }
+
+    fn varint(&mut self, number: u64, value: u64) {
+        self.key(number, 0);
+        self.raw_varint(value);
+    }
+
+    // fixed64, sfixed64 and double are all 8 bytes little endian
+    fn fixed64(&mut self, number: u64, value: u64) {
+        self.key(number, 1);
+        for i in 0..8 {
+            self.buf.push((value >> (8 * i)) as u8);
+        }
+    }
+
+    fn double(&mut self, number: u64, value: f64) {
+        self.fixed64(number, unsafe { mem::transmute::<f64, u64>(value) });
+    }
+
+    fn bytes(&mut self, number: u64, bytes: &[u8]) {
+        self.key(number, 2);
+        self.raw_varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, number: u64, value: &str) {
+        self.bytes(number, value.as_bytes());
+    }
+
+    fn message(&mut self, number: u64, message: &Message) {
+        self.bytes(number, &message.buf);
+    }
+}
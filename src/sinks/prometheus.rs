@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use time;
+
+use config::Prometheus;
+use metric::{Metric, Value};
+use sinks::{Sink, PACKET_MEASUREMENT};
+
+// Series that haven't been updated for this long belong to daemons that
+// have gone away
+const STALE_SECS: i64 = 600;
+
+// Too many distinct values to be labels
+const PACKET_DROPPED_TAGS: &'static [&'static str] = &["src_ip", "dst_ip"];
+
+#[cfg(test)]
+mod tests {
+    use metric::{Metric, Value};
+
+    #[test]
+    fn test_render() {
+        let mut registry = super::Registry::new();
+
+        let mut osd = Metric::new("osd_daemon");
+        osd.add_tag("hostname", "ceph-1");
+        osd.add_tag("osd_num", "3");
+        osd.add_tag("drive_name", "/dev/sdb1");
+        osd.add_field("stat_bytes", Value::Integer(466472001536));
+        osd.add_counter("op_latency", Value::Float(379.5));
+        registry.record(&osd);
+
+        let mut op = Metric::new("osd_operation");
+        op.add_tag("hostname", "ceph-1");
+        op.add_tag("type", "write");
+        op.add_tag("src_ip", "10.0.0.1");
+        op.add_field("size", Value::Integer(4096));
+        op.add_field("operation", Value::String("WRITE".to_string()));
+        registry.record(&op);
+        registry.record(&op);
+
+        let now = osd.timestamp.sec;
+        assert_eq!(registry.render(now),
+                   "# HELP ceph_osd_daemon_op_latency_total op_latency from osd_daemon\n\
+                    # TYPE ceph_osd_daemon_op_latency_total counter\n\
+                    ceph_osd_daemon_op_latency_total{drive_name=\"/dev/sdb1\",hostname=\"ceph-1\",osd_num=\"3\"} 379.5\n\
+                    # HELP ceph_osd_daemon_stat_bytes stat_bytes from osd_daemon\n\
+                    # TYPE ceph_osd_daemon_stat_bytes gauge\n\
+                    ceph_osd_daemon_stat_bytes{drive_name=\"/dev/sdb1\",hostname=\"ceph-1\",osd_num=\"3\"} 466472001536\n\
+                    # HELP ceph_osd_operation_bytes_total Bytes in captured OSD operations\n\
+                    # TYPE ceph_osd_operation_bytes_total counter\n\
+                    ceph_osd_operation_bytes_total{hostname=\"ceph-1\",type=\"write\"} 8192\n\
+                    # HELP ceph_osd_operation_total Captured OSD operations\n\
+                    # TYPE ceph_osd_operation_total counter\n\
+                    ceph_osd_operation_total{hostname=\"ceph-1\",type=\"write\"} 2\n");
+
+        assert_eq!(registry.render(now + super::STALE_SECS + 1), "");
+    }
+
+    #[test]
+    fn test_format_value() {
+        use std::f64;
+
+        assert_eq!(super::format_value(0.5), "0.5");
+        assert_eq!(super::format_value(466472001536.0), "466472001536");
+        assert_eq!(super::format_value(f64::INFINITY), "+Inf");
+        assert_eq!(super::format_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(super::format_value(f64::NAN), "NaN");
+    }
+}
+
+/// The HTTP server behind `/metrics`.  hyper can't stop listening once it
+/// has started, so this lives for as long as the process and the sinks
+/// built on each reload share it.
+pub struct Exporter {
+    listen: String,
+    registry: Arc<Mutex<Registry>>,
+    _listening: Listening,
+}
+
+impl Exporter {
+    pub fn start(prometheus: &Prometheus) -> Result<Exporter, String> {
+        let registry = Arc::new(Mutex::new(Registry::new()));
+        let server = try!(Server::http(&prometheus.listen[..]).map_err(|e| e.to_string()));
+        let listening = try!(server.handle(MetricsHandler { registry: registry.clone() }).map_err(|e| e.to_string()));
+        info!("Serving Prometheus metrics on http://{}/metrics", prometheus.listen);
+        Ok(Exporter {
+            listen: prometheus.listen.clone(),
+            registry: registry,
+            _listening: listening,
+        })
+    }
+
+    pub fn sink(&self, prometheus: &Prometheus) -> PrometheusSink {
+        if prometheus.listen != self.listen {
+            warn!("prometheus.listen changed to {}, still listening on {} until restarted",
+                  prometheus.listen, self.listen);
+        }
+        PrometheusSink { registry: self.registry.clone() }
+    }
+}
+
+pub struct PrometheusSink {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Sink for PrometheusSink {
+    fn write(&mut self, metric: &Metric) {
+        self.registry.lock().unwrap().record(metric);
+    }
+}
+
+struct MetricsHandler {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Handler for MetricsHandler {
+    fn handle(&self, req: Request, mut res: Response) {
+        let is_metrics = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.split('?').next() == Some("/metrics"),
+            _ => false,
+        };
+        if !is_metrics {
+            *res.status_mut() = StatusCode::NotFound;
+            let _ = res.send(b"Not found, try /metrics\n");
+            return;
+        }
+        let body = self.registry.lock().unwrap().render(time::get_time().sec);
+        res.headers_mut().set_raw("Content-Type", vec![b"text/plain; version=0.0.4".to_vec()]);
+        if let Err(e) = res.send(body.as_bytes()) {
+            debug!("Unable to answer a Prometheus scrape: {}", e);
+        }
+    }
+}
+
+struct Family {
+    kind: &'static str,
+    help: String,
+    // Keyed by the rendered label set
+    samples: BTreeMap<String, Sample>,
+}
+
+struct Sample {
+    value: f64,
+    updated: i64,
+}
+
+/// The latest value of every series
+struct Registry {
+    families: BTreeMap<String, Family>,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry { families: BTreeMap::new() }
+    }
+
+    fn record(&mut self, metric: &Metric) {
+        let now = metric.timestamp.sec;
+        if metric.name == PACKET_MEASUREMENT {
+            let labels = labels(metric, PACKET_DROPPED_TAGS);
+            let size = match metric.fields.get("size") {
+                Some(&Value::Integer(size)) => size as f64,
+                _ => 0.0,
+            };
+            self.add("ceph_osd_operation_total", "Captured OSD operations", &labels, 1.0, now);
+            self.add("ceph_osd_operation_bytes_total", "Bytes in captured OSD operations", &labels, size, now);
+            return;
+        }
+        let labels = labels(metric, &["type"]);
+        for (key, value) in metric.fields.iter() {
+            let value = match *value {
+                Value::Integer(i) => i as f64,
+                Value::Float(f) => f,
+                Value::Boolean(b) => if b { 1.0 } else { 0.0 },
+                Value::String(_) => continue,
+            };
+            let help = format!("{} from {}", key, metric.name);
+            let name = format!("ceph_{}_{}", sanitize(&metric.name), sanitize(key));
+            let (name, kind) = if metric.is_counter(key) {
+                (format!("{}_total", name), "counter")
+            } else {
+                (name, "gauge")
+            };
+            let family = self.family(name, kind, help);
+            family.samples.insert(labels.clone(), Sample { value: value, updated: now });
+        }
+    }
+
+    fn add(&mut self, name: &str, help: &str, labels: &str, delta: f64, now: i64) {
+        let family = self.family(name.to_string(), "counter", help.to_string());
+        let sample = family.samples.entry(labels.to_string()).or_insert(Sample { value: 0.0, updated: now });
+        sample.value += delta;
+        sample.updated = now;
+    }
+
+    fn family(&mut self, name: String, kind: &'static str, help: String) -> &mut Family {
+        self.families.entry(name).or_insert(Family {
+            kind: kind,
+            help: help,
+            samples: BTreeMap::new(),
+        })
+    }
+
+    // Text exposition format, dropping anything stale on the way
+    fn render(&mut self, now: i64) -> String {
+        for family in self.families.values_mut() {
+            let stale: Vec<String> = family.samples.iter()
+                                                   .filter(|&(_, s)| now - s.updated > STALE_SECS)
+                                                   .map(|(labels, _)| labels.clone())
+                                                   .collect();
+            for labels in stale {
+                family.samples.remove(&labels);
+            }
+        }
+        let empty: Vec<String> = self.families.iter()
+                                              .filter(|&(_, f)| f.samples.is_empty())
+                                              .map(|(name, _)| name.clone())
+                                              .collect();
+        for name in empty {
+            self.families.remove(&name);
+        }
+
+        let mut out = String::new();
+        for (name, family) in self.families.iter() {
+            out.push_str(&format!("# HELP {} {}\n", name, family.help));
+            out.push_str(&format!("# TYPE {} {}\n", name, family.kind));
+            for (labels, sample) in family.samples.iter() {
+                out.push_str(&format!("{}{} {}\n", name, labels, format_value(sample.value)));
+            }
+        }
+        out
+    }
+}
+
+// `{key="value",...}` from the metric's tags, sorted since tags are
+fn labels(metric: &Metric, skip: &[&str]) -> String {
+    let pairs: Vec<String> = metric.tags.iter()
+                                        .filter(|&(key, _)| !skip.contains(&&key[..]))
+                                        .map(|(key, value)| format!("{}=\"{}\"", sanitize(key), escape(value)))
+                                        .collect();
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+// Metric and label names may only contain [a-zA-Z0-9_]
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => c,
+        _ => '_',
+    }).collect()
+}
+
+fn escape(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+// Rust writes infinity as `inf`, which the exposition format spells `+Inf`
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    } else {
+        format!("{}", value)
+    }
+}
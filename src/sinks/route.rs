@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use regex::Regex;
+
+use config::{Filter, Route};
+use metric::Metric;
+use sinks::Sink;
+
+#[cfg(test)]
+mod tests {
+    use config::{Filter, Route};
+    use metric::{Metric, Value};
+
+    #[test]
+    fn test_route() {
+        let route = Route {
+            measurements: Filter { include: vec![], exclude: vec!["^osd_operation$".to_string()] },
+            fields: Filter { include: vec!["^op_".to_string(), "^stat_".to_string()], exclude: vec!["_bytes$".to_string()] },
+            rename_fields: vec![("op_latency".to_string(), "op_latency_seconds".to_string())].into_iter().collect(),
+            drop_tags: vec!["drive_name".to_string()],
+        };
+        let rules = super::Rules::new(&route);
+
+        let mut osd = Metric::new("osd_daemon");
+        osd.add_tag("osd_num", "3");
+        osd.add_tag("drive_name", "/dev/sdb1");
+        osd.add_counter("op_latency", Value::Float(379.5));
+        osd.add_field("op_w", Value::Integer(10));
+        osd.add_field("stat_bytes", Value::Integer(466472001536));
+        osd.add_field("numpg", Value::Integer(128));
+        let routed = rules.apply(&osd).unwrap();
+        assert_eq!(routed.fields.keys().collect::<Vec<_>>(), vec!["op_latency_seconds", "op_w"]);
+        assert!(routed.is_counter("op_latency_seconds"));
+        assert_eq!(routed.tags.keys().collect::<Vec<_>>(), vec!["osd_num"]);
+
+        let mut op = Metric::new("osd_operation");
+        op.add_field("size", Value::Integer(4096));
+        assert!(rules.apply(&op).is_none());
+        // Nothing left to send
+        let mut mon = Metric::new("mon_daemon");
+        mon.add_field("osds", Value::Integer(3));
+        assert!(rules.apply(&mon).is_none());
+    }
+}
+
+/// An output's sink behind its routing rules from `routes:`
+pub struct RoutedSink {
+    rules: Rules,
+    sink: Box<Sink>,
+}
+
+impl RoutedSink {
+    pub fn new(route: &Route, sink: Box<Sink>) -> RoutedSink {
+        RoutedSink {
+            rules: Rules::new(route),
+            sink: sink,
+        }
+    }
+}
+
+impl Sink for RoutedSink {
+    fn write(&mut self, metric: &Metric) {
+        if let Some(metric) = self.rules.apply(metric) {
+            self.sink.write(&metric);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.sink.flush();
+    }
+}
+
+struct Rules {
+    measurements: Patterns,
+    fields: Patterns,
+    rename_fields: BTreeMap<String, String>,
+    drop_tags: Vec<String>,
+}
+
+impl Rules {
+    fn new(route: &Route) -> Rules {
+        Rules {
+            measurements: Patterns::new(&route.measurements),
+            fields: Patterns::new(&route.fields),
+            rename_fields: route.rename_fields.clone(),
+            drop_tags: route.drop_tags.clone(),
+        }
+    }
+
+    // The metric as this output should see it, if it should see it at all
+    fn apply(&self, metric: &Metric) -> Option<Metric> {
+        if !self.measurements.allow(&metric.name) {
+            return None;
+        }
+        let mut routed = Metric::new(&metric.name);
+        routed.timestamp = metric.timestamp;
+        for (key, value) in metric.tags.iter() {
+            if !self.drop_tags.contains(key) {
+                routed.tags.insert(key.clone(), value.clone());
+            }
+        }
+        for (key, value) in metric.fields.iter() {
+            if !self.fields.allow(key) {
+                continue;
+            }
+            let name = self.rename_fields.get(key).unwrap_or(key);
+            routed.fields.insert(name.clone(), value.clone());
+            if metric.is_counter(key) {
+                routed.counters.insert(name.clone());
+            }
+        }
+        if routed.fields.is_empty() {
+            return None;
+        }
+        Some(routed)
+    }
+}
+
+struct Patterns {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl Patterns {
+    // The patterns were checked when the config was loaded
+    fn new(filter: &Filter) -> Patterns {
+        let compile = |patterns: &Vec<String>| patterns.iter().filter_map(|p| Regex::new(p).ok()).collect();
+        Patterns {
+            include: compile(&filter.include),
+            exclude: compile(&filter.exclude),
+        }
+    }
+
+    fn allow(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|r| r.is_match(name))) &&
+            !self.exclude.iter().any(|r| r.is_match(name))
+    }
+}
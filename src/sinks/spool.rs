@@ -0,0 +1,234 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sinks::now_ms;
+
+// Backoff between replay attempts while the backend is down
+const MIN_BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF_MS: u64 = 300000;
+
+// Each batch starts with a line naming where it was to be written, which
+// line protocol takes as a comment
+const TARGET_PREFIX: &'static str = "# ";
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use sinks::mock::scratch_dir;
+
+    // Last in line, as `push` was before places could be taken ahead
+    fn push(spool: &mut super::Spool, batch: &str) -> u64 {
+        let seq = spool.reserve();
+        spool.push(seq, "/w", batch).unwrap()
+    }
+
+    #[test]
+    fn test_spool() {
+        let dir = scratch_dir("spool");
+        let mut spool = super::Spool::new(&dir, 30);
+        // Each with a 5 byte `# /w` line in front
+        assert_eq!(push(&mut spool, "a 1\nb 2\n"), 0);
+        assert_eq!(push(&mut spool, "c 3\n"), 0);
+        assert_eq!(spool.bytes(), 22);
+
+        // Picked up again after a restart, in the same order
+        let mut spool = super::Spool::new(&dir, 30);
+        assert_eq!(spool.bytes(), 22);
+        let (path, target, batch) = spool.oldest().unwrap().unwrap();
+        assert_eq!(target, Some("/w".to_string()));
+        assert_eq!(batch, "a 1\nb 2\n");
+        // Going over the cap drops the oldest batch and its two points
+        assert_eq!(push(&mut spool, "d 4\ne 5\nf 6\n"), 2);
+        assert!(!path.exists());
+        assert_eq!(spool.oldest().unwrap().unwrap().2, "c 3\n");
+        spool.remove();
+        assert_eq!(spool.oldest().unwrap().unwrap().2, "d 4\ne 5\nf 6\n");
+        spool.remove();
+        assert!(spool.oldest().unwrap().is_none());
+
+        // A batch given its place before a newer one was pushed still goes
+        // in front of it
+        let seq = spool.reserve();
+        let newer = spool.reserve();
+        spool.push(newer, "/w", "h 8\n").unwrap();
+        spool.push(seq, "/w", "i 9\n").unwrap();
+        assert_eq!(spool.oldest().unwrap().unwrap().2, "i 9\n");
+        spool.remove();
+        assert_eq!(spool.oldest().unwrap().unwrap().2, "h 8\n");
+        spool.remove();
+
+        // Spooled before batches said where they were for
+        File::create(dir.join(format!("{:020}.lp", 7))).unwrap().write_all(b"g 7\n").unwrap();
+        let spool = super::Spool::new(&dir, 30);
+        assert_eq!(spool.oldest().unwrap().unwrap().1, None);
+        assert_eq!(spool.oldest().unwrap().unwrap().2, "g 7\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+/// Batches that couldn't be sent, one file each, oldest first, kept until
+/// they are or the spool grows past `max_bytes`
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    // Sequence numbers and sizes of the batches on disk
+    batches: Vec<(u64, u64)>,
+    next: u64,
+    retry_at: u64,
+    backoff_ms: u64,
+}
+
+impl Spool {
+    /// Picks up anything a previous run left in `dir`
+    pub fn new(dir: &Path, max_bytes: u64) -> Spool {
+        let mut batches = Vec::new();
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                let seq = entry.file_name().to_str()
+                               .and_then(|name| if name.ends_with(".lp") { Some(name.trim_right_matches(".lp")) } else { None })
+                               .and_then(|seq| seq.parse::<u64>().ok());
+                if let Some(seq) = seq {
+                    let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                    batches.push((seq, size));
+                }
+            }
+        }
+        batches.sort();
+        if !batches.is_empty() {
+            info!("Found {} unsent batches in {}", batches.len(), dir.display());
+        }
+        let next = batches.last().map_or(0, |&(seq, _)| seq + 1);
+        Spool {
+            dir: dir.to_path_buf(),
+            max_bytes: max_bytes,
+            batches: batches,
+            next: next,
+            retry_at: 0,
+            backoff_ms: MIN_BACKOFF_MS,
+        }
+    }
+
+    /// Takes effect with the next push
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.batches.iter().fold(0, |total, &(_, size)| total + size)
+    }
+
+    /// A place in line for a batch that may have to be spooled later, so
+    /// that it goes in front of any pushed in the meantime
+    pub fn reserve(&mut self) -> u64 {
+        self.next += 1;
+        self.next - 1
+    }
+
+    /// Queue a batch of lines for `target` in the place `seq` that
+    /// `reserve` gave it, returning how many lines were dropped to stay
+    /// under the cap
+    pub fn push(&mut self, seq: u64, target: &str, batch: &str) -> io::Result<u64> {
+        try!(fs::create_dir_all(&self.dir));
+        let path = self.path(seq);
+        let partial = path.with_extension("tmp");
+        let contents = format!("{}{}\n{}", TARGET_PREFIX, target, batch);
+        try!(try!(File::create(&partial)).write_all(contents.as_bytes()));
+        try!(fs::rename(&partial, &path));
+        let at = self.batches.iter().position(|&(s, _)| s > seq).unwrap_or(self.batches.len());
+        self.batches.insert(at, (seq, contents.len() as u64));
+        if seq >= self.next {
+            self.next = seq + 1;
+        }
+
+        let mut dropped = 0;
+        // Always keep the newest, however big it is
+        while self.batches.len() > 1 && self.bytes() > self.max_bytes {
+            dropped += match self.read(self.batches[0].0) {
+                Ok((_, batch)) => batch.lines().count() as u64,
+                Err(_) => 0,
+            };
+            self.remove();
+        }
+        Ok(dropped)
+    }
+
+    /// The oldest batch, where it's kept and its target, if it was spooled
+    /// with one
+    pub fn oldest(&self) -> io::Result<Option<(PathBuf, Option<String>, String)>> {
+        match self.batches.first() {
+            Some(&(seq, _)) => {
+                let (target, batch) = try!(self.read(seq));
+                Ok(Some((self.path(seq), target, batch)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Forget the oldest batch, once it's been sent or can't be
+    pub fn remove(&mut self) {
+        if self.batches.is_empty() {
+            return;
+        }
+        let (seq, _) = self.batches.remove(0);
+        if let Err(e) = fs::remove_file(self.path(seq)) {
+            warn!("Unable to remove {}: {}", self.path(seq).display(), e);
+        }
+    }
+
+    /// Forget the batch at `path` if it's still the oldest, it may have been
+    /// dropped to make room while it was being sent
+    pub fn remove_if_oldest(&mut self, path: &Path) {
+        let oldest = self.batches.first().map(|&(seq, _)| self.path(seq));
+        if oldest.as_ref().map(|p| p.as_path()) == Some(path) {
+            self.remove();
+        }
+    }
+
+    /// Whether the backoff since the last failure is over
+    pub fn due(&self) -> bool {
+        now_ms() >= self.retry_at
+    }
+
+    pub fn succeeded(&mut self) {
+        self.backoff_ms = MIN_BACKOFF_MS;
+        self.retry_at = 0;
+    }
+
+    /// Wait twice as long as last time before trying again
+    pub fn failed(&mut self) {
+        self.retry_at = now_ms() + self.backoff_ms;
+        self.backoff_ms = ::std::cmp::min(self.backoff_ms * 2, MAX_BACKOFF_MS);
+    }
+
+    fn read(&self, seq: u64) -> io::Result<(Option<String>, String)> {
+        let mut contents = String::new();
+        try!(try!(File::open(self.path(seq))).read_to_string(&mut contents));
+        if contents.starts_with(TARGET_PREFIX) {
+            if let Some(end) = contents.find('\n') {
+                let target = contents[TARGET_PREFIX.len()..end].to_string();
+                return Ok((Some(target), contents[end + 1..].to_string()));
+            }
+        }
+        Ok((None, contents))
+    }
+
+    // Zero padded so the files list in order too
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.lp", seq))
+    }
+}
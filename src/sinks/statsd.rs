@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+
+use config::{Graphite, Statsd};
+use metric::{Metric, Value};
+use sinks::{self, Sink, MAX_DATAGRAM_BYTES, PACKET_MEASUREMENT};
+use sinks::graphite::{component, metric_path};
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use config::{Graphite, Statsd};
+    use metric::{Metric, Value};
+    use sinks::Sink;
+
+    fn osd(ops: i64) -> Metric {
+        let mut metric = Metric::new("osd_daemon");
+        metric.add_tag("hostname", "ceph-1");
+        metric.add_tag("cluster", "ceph");
+        metric.add_tag("osd_num", "3");
+        metric.add_field("stat_bytes_used", Value::Integer(1024));
+        metric.add_counter("ops", Value::Integer(ops));
+        metric
+    }
+
+    fn graphite() -> Graphite {
+        Graphite {
+            host: String::new(),
+            port: 0,
+            protocol: ::config::GraphiteProtocol::Plaintext,
+            templates: vec![("osd_daemon".to_string(), "{cluster}.{hostname}.osd.{osd_num}".to_string())].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn test_lines() {
+        let graphite = graphite();
+        let mut sink = super::StatsdSink::new(&Statsd::default(), &graphite);
+        // Counters need a previous value to send an increase
+        assert_eq!(sink.lines(&osd(100)), vec!["ceph.ceph-1.osd.3.stat_bytes_used:1024|g"]);
+        assert_eq!(sink.lines(&osd(150)), vec!["ceph.ceph-1.osd.3.ops:50|c", "ceph.ceph-1.osd.3.stat_bytes_used:1024|g"]);
+        // The OSD restarted
+        assert_eq!(sink.lines(&osd(10)), vec!["ceph.ceph-1.osd.3.stat_bytes_used:1024|g"]);
+
+        let mut statsd = Statsd::default();
+        statsd.dogstatsd = true;
+        let mut sink = super::StatsdSink::new(&statsd, &graphite);
+        let mut op = Metric::new("osd_operation");
+        op.add_tag("type", "write");
+        op.add_field("size", Value::Integer(4096));
+        op.add_field("operation", Value::String("WRITE".to_string()));
+        assert_eq!(sink.lines(&op), vec!["ceph.osd_operation.size:4096|c|#type:write"]);
+    }
+
+    #[test]
+    fn test_ipv6() {
+        // Not every host has IPv6, even on the loopback
+        let listener = match UdpSocket::bind("[::1]:0") {
+            Ok(listener) => listener,
+            Err(_) => return,
+        };
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut statsd = Statsd::default();
+        statsd.host = "::1".to_string();
+        statsd.port = listener.local_addr().unwrap().port();
+        statsd.dogstatsd = true;
+
+        let mut sink = super::StatsdSink::new(&statsd, &graphite());
+        let mut op = Metric::new("osd_operation");
+        op.add_field("size", Value::Integer(4096));
+        sink.write(&op);
+        let mut buf = [0u8; 2048];
+        let len = listener.recv_from(&mut buf).unwrap().0;
+        assert_eq!(&buf[..len], &b"ceph.osd_operation.size:4096|c"[..]);
+        assert_eq!(sink.socket.as_ref().map(|&(_, addr)| addr), Some(listener.local_addr().unwrap()));
+    }
+}
+
+pub struct StatsdSink {
+    statsd: Statsd,
+    graphite: Graphite,
+    // Opened for the address `host` resolved to, which is looked up again
+    // once a send fails
+    socket: Option<(UdpSocket, SocketAddr)>,
+    // The last value of every counter, StatsD wants the increase
+    last: HashMap<String, f64>,
+}
+
+impl StatsdSink {
+    pub fn new(statsd: &Statsd, graphite: &Graphite) -> StatsdSink {
+        StatsdSink {
+            statsd: statsd.clone(),
+            graphite: graphite.clone(),
+            socket: None,
+            last: HashMap::new(),
+        }
+    }
+
+    fn lines(&mut self, metric: &Metric) -> Vec<String> {
+        let (base, tags) = if self.statsd.dogstatsd {
+            (format!("{}.{}", self.statsd.prefix, component(&metric.name)), dogstatsd_tags(metric))
+        } else {
+            (metric_path(self.graphite.template(&metric.name), metric), String::new())
+        };
+        let mut lines = Vec::new();
+        for (key, value) in metric.fields.iter() {
+            let value = match *value {
+                Value::Integer(i) => i as f64,
+                Value::Float(f) if f.is_finite() => f,
+                Value::Boolean(b) => if b { 1.0 } else { 0.0 },
+                _ => continue,
+            };
+            let name = format!("{}.{}", base, component(key));
+            if metric.name == PACKET_MEASUREMENT {
+                lines.push(format!("{}:{}|c{}", name, value, tags));
+            } else if metric.is_counter(key) {
+                match self.last.insert(format!("{}{}", name, tags), value) {
+                    // Nothing is sent for the first reading, or after the
+                    // daemon restarted and its counters went back to zero
+                    Some(previous) if value >= previous => {
+                        lines.push(format!("{}:{}|c{}", name, value - previous, tags));
+                    },
+                    _ => {},
+                }
+            } else {
+                // A signed gauge value is taken as a change, so negative
+                // values have to be set from zero
+                if value < 0.0 {
+                    lines.push(format!("{}:0|g{}", name, tags));
+                }
+                lines.push(format!("{}:{}|g{}", name, value, tags));
+            }
+        }
+        lines
+    }
+
+    fn send(&mut self, packet: &str) {
+        if self.socket.is_none() {
+            match sinks::open_udp(&self.statsd.host, self.statsd.port) {
+                Ok(socket) => self.socket = Some(socket),
+                Err(e) => {
+                    debug!("Unable to open a socket for StatsD at {}:{}: {}", self.statsd.host, self.statsd.port, e);
+                    return;
+                }
+            }
+        }
+        let result = match self.socket {
+            Some((ref socket, addr)) => socket.send_to(packet.as_bytes(), addr),
+            None => return,
+        };
+        if let Err(e) = result {
+            // The host may have moved, look it up again next time
+            self.socket = None;
+            debug!("Unable to send to StatsD at {}:{}: {}", self.statsd.host, self.statsd.port, e);
+        }
+    }
+}
+
+impl Sink for StatsdSink {
+    fn write(&mut self, metric: &Metric) {
+        let mut packet = String::new();
+        for line in self.lines(metric) {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_DATAGRAM_BYTES {
+                self.send(&packet);
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            self.send(&packet);
+        }
+    }
+}
+
+// `|#key:value,...`, with the separators DogStatsD uses taken out of values
+fn dogstatsd_tags(metric: &Metric) -> String {
+    if metric.tags.is_empty() {
+        return String::new();
+    }
+    let tags: Vec<String> = metric.tags.iter()
+                                       .map(|(key, value)| format!("{}:{}", key, value.replace(",", "_").replace("|", "_")))
+                                       .collect();
+    format!("|#{}", tags.join(","))
+}
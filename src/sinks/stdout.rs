@@ -0,0 +1,40 @@
+use std::io::{self, Write};
+
+use config::{Format, Stdout, Stream};
+use metric::Metric;
+use sinks::Sink;
+
+/// Writes one measurement per line, for piping into other tools or seeing
+/// what would be sent elsewhere
+pub struct StdoutSink {
+    format: Format,
+    stream: Stream,
+}
+
+impl StdoutSink {
+    pub fn new(stdout: &Stdout) -> StdoutSink {
+        StdoutSink {
+            format: stdout.format,
+            stream: stdout.stream,
+        }
+    }
+}
+
+impl Sink for StdoutSink {
+    fn write(&mut self, metric: &Metric) {
+        let line = match self.format {
+            Format::Json => metric.to_json(),
+            Format::LineProtocol => match metric.to_line_protocol() {
+                Some(line) => line,
+                None => return,
+            },
+        };
+        let result = match self.stream {
+            Stream::Stdout => writeln!(&mut io::stdout(), "{}", line),
+            Stream::Stderr => writeln!(&mut io::stderr(), "{}", line),
+        };
+        if let Err(e) = result {
+            debug!("Unable to write {} to {:?}: {}", metric.name, self.stream, e);
+        }
+    }
+}